@@ -0,0 +1,217 @@
+use std::collections::{ HashMap, HashSet };
+use std::sync::{ Mutex, MutexGuard, OnceLock };
+use std::time::Duration;
+use chrono::Utc;
+use socketioxide::{ extract::SocketRef, socket::Sid };
+use tokio_util::sync::CancellationToken;
+use crate::{ filter, models::*, shutdown };
+
+const MIN_WINDOW_MS: u64 = 50;
+const MAX_WINDOW_MS: u64 = 60 * 60 * 1000;
+const MAX_RESAMPLE_HZ: f64 = 50.0;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    // min/max/mean/count per window
+    Window,
+    // Latest value of every peripheral at a fixed rate
+    Resample,
+}
+
+struct Stats {
+    count: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+}
+
+// One client's aggregated stream
+struct Aggregation {
+    mode: Mode,
+    macs: Option<HashSet<String>>,
+    window: HashMap<String, Stats>,
+    window_start: String,
+    latest: HashMap<String, (f64, String)>,
+    // Stops the client's ticker
+    ticker: CancellationToken,
+}
+
+static AGGREGATIONS: OnceLock<Mutex<HashMap<Sid, Aggregation>>> = OnceLock::new();
+
+fn aggregations() -> MutexGuard<'static, HashMap<Sid, Aggregation>> {
+    AGGREGATIONS.get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn is_empty() -> bool {
+    aggregations().is_empty()
+}
+
+// Feed an emitted event to every aggregation that covers its peripheral
+pub fn observe(event: &SnapDataEvent) {
+    let mut aggregations = aggregations();
+    if aggregations.is_empty() {
+        return;
+    }
+    let value = event.processed_value();
+    for aggregation in aggregations.values_mut() {
+        if aggregation.macs.as_ref().is_some_and(|macs| !macs.contains(&event.mac)) {
+            continue;
+        }
+        match aggregation.mode {
+            Mode::Window => {
+                let stats = aggregation.window.entry(event.mac.clone()).or_insert(Stats {
+                    count: 0,
+                    min: value,
+                    max: value,
+                    sum: 0.0,
+                });
+                stats.count += 1;
+                stats.min = stats.min.min(value);
+                stats.max = stats.max.max(value);
+                stats.sum += value;
+            }
+            Mode::Resample => {
+                aggregation.latest.insert(event.mac.clone(), (value, event.timestamp.clone()));
+            }
+        }
+    }
+}
+
+// Start (or replace) the client's aggregated stream
+pub fn subscribe(socket: &SocketRef, request: AggregateRequest) -> Result<AggregateRequest, String> {
+    let (mode, interval) = match (request.window_ms, request.resample_hz) {
+        (Some(window_ms), None) if (MIN_WINDOW_MS..=MAX_WINDOW_MS).contains(&window_ms) =>
+            (Mode::Window, Duration::from_millis(window_ms)),
+        (Some(_), None) => {
+            return Err(format!("window_ms must be between {} and {}", MIN_WINDOW_MS, MAX_WINDOW_MS));
+        }
+        (None, Some(hz)) if hz > 0.0 && hz <= MAX_RESAMPLE_HZ => (Mode::Resample, Duration::from_secs_f64(1.0 / hz)),
+        (None, Some(_)) => {
+            return Err(format!("resample_hz must be above 0 and at most {}", MAX_RESAMPLE_HZ));
+        }
+        _ => {
+            return Err("expects either { window_ms } or { resample_hz }, optionally with macs".to_string());
+        }
+    };
+    let macs = request.macs.as_deref().map(filter::parse_macs).transpose()?;
+
+    let ticker = shutdown::token().child_token();
+    let aggregation = Aggregation {
+        mode,
+        macs: macs.clone(),
+        window: HashMap::new(),
+        window_start: Utc::now().to_rfc3339(),
+        latest: HashMap::new(),
+        ticker: ticker.clone(),
+    };
+    if let Some(previous) = aggregations().insert(socket.id, aggregation) {
+        previous.ticker.cancel();
+    }
+
+    let socket = socket.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = ticker.cancelled() => break,
+            }
+            tick(&socket);
+        }
+    });
+
+    let mut macs: Option<Vec<String>> = macs.map(|macs| macs.into_iter().collect());
+    if let Some(macs) = macs.as_mut() {
+        macs.sort();
+    }
+    Ok(AggregateRequest { macs, ..request })
+}
+
+pub fn unsubscribe(sid: Sid) -> bool {
+    match aggregations().remove(&sid) {
+        Some(aggregation) => {
+            aggregation.ticker.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+fn sorted_by_mac<T>(mut items: Vec<(String, T)>) -> Vec<(String, T)> {
+    items.sort_by(|a, b| a.0.cmp(&b.0));
+    items
+}
+
+// Close the client's current window, or sample the held values
+fn tick(socket: &SocketRef) {
+    let now = Utc::now().to_rfc3339();
+    let mut aggregations = aggregations();
+    let Some(aggregation) = aggregations.get_mut(&socket.id) else {
+        return;
+    };
+    match aggregation.mode {
+        Mode::Window => {
+            let window_start = std::mem::replace(&mut aggregation.window_start, now.clone());
+            let window: Vec<(String, Stats)> = aggregation.window.drain().collect();
+            // Windows without data are not sent
+            if window.is_empty() {
+                return;
+            }
+            let event = AggregateEvent {
+                window_start,
+                window_end: now,
+                stats: sorted_by_mac(window)
+                    .into_iter()
+                    .map(|(mac, stats)| AggregateStats {
+                        mac,
+                        count: stats.count,
+                        min: stats.min,
+                        max: stats.max,
+                        mean: stats.sum / (stats.count as f64),
+                    })
+                    .collect(),
+            };
+            let _ = socket.emit("snappy-aggregate", &event);
+        }
+        Mode::Resample => {
+            if aggregation.latest.is_empty() {
+                return;
+            }
+            let latest: Vec<(String, (f64, String))> = aggregation.latest
+                .iter()
+                .map(|(mac, latest)| (mac.clone(), latest.clone()))
+                .collect();
+            let event = ResampledEvent {
+                timestamp: now,
+                values: sorted_by_mac(latest)
+                    .into_iter()
+                    .map(|(mac, (value, timestamp))| ResampledValue { mac, value, timestamp })
+                    .collect(),
+            };
+            let _ = socket.emit("snappy-resampled", &event);
+        }
+    }
+}
+
+pub fn response(command: &str, result: Result<Option<AggregateRequest>, String>) -> AggregateResponse {
+    match result {
+        Ok(subscription) =>
+            AggregateResponse {
+                success: true,
+                command: command.to_string(),
+                subscription,
+                error: None,
+            },
+        Err(e) =>
+            AggregateResponse {
+                success: false,
+                command: command.to_string(),
+                subscription: None,
+                error: Some(e),
+            },
+    }
+}
@@ -0,0 +1,61 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+// Command line options understood by the agent
+#[derive(Clone, Debug, Default)]
+pub struct Args {
+    pub service: bool,
+    pub config: Option<PathBuf>,
+    pub listen: Vec<IpAddr>,
+    pub unix_socket: Option<PathBuf>,
+    pub tls: bool,
+    pub replace: bool,
+    pub export_ca: Option<PathBuf>,
+    pub user: Option<String>,
+    pub self_check: bool,
+}
+
+pub fn parse() -> Args {
+    let mut parsed = Args::default();
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--service" => {
+                parsed.service = true;
+            }
+            "--config" => {
+                parsed.config = args.next().map(PathBuf::from);
+            }
+            "--listen" => {
+                match args.next().map(|value| value.parse::<IpAddr>()) {
+                    Some(Ok(ip)) => parsed.listen.push(ip),
+                    _ => eprintln!("--listen expects an IP address"),
+                }
+            }
+            "--unix-socket" => {
+                parsed.unix_socket = args.next().map(PathBuf::from);
+            }
+            "--tls" => {
+                parsed.tls = true;
+            }
+            "--replace" => {
+                parsed.replace = true;
+            }
+            "--export-ca" => {
+                parsed.export_ca = args.next().map(PathBuf::from);
+            }
+            "--user" => {
+                parsed.user = args.next();
+            }
+            "--self-check" => {
+                parsed.self_check = true;
+            }
+            other => {
+                eprintln!("Ignoring unknown argument: {}", other);
+            }
+        }
+    }
+
+    parsed
+}
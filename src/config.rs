@@ -0,0 +1,356 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use std::path::{ Path, PathBuf };
+use std::sync::OnceLock;
+use tracing::info;
+use crate::{ cli::Args, instance::OnConflict, models::MacFilterLists, paths };
+
+static CONFIG: OnceLock<AgentConfig> = OnceLock::new();
+
+// Agent configuration, read from config.toml. Every section is optional.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct AgentConfig {
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub instance: InstanceConfig,
+    pub privileges: PrivilegesConfig,
+    pub store: StoreConfig,
+    pub stream: StreamConfig,
+    pub peripherals: PeripheralsConfig,
+    // Initial MAC allow/deny lists, until they are edited at runtime
+    pub filter: MacFilterLists,
+    pub transform: TransformConfig,
+    pub processing: ProcessingConfig,
+    pub rules: Vec<TriggerRule>,
+    pub scripting: ScriptingConfig,
+    pub device: DeviceConfig,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct DeviceConfig {
+    // The serial number keys the dongle's encryption, so it is not sent to clients by default
+    pub expose_serial: bool,
+    // Profile names by PID ("0x5508" or decimal), reported in `device-status`
+    pub profiles: HashMap<String, String>,
+}
+
+impl DeviceConfig {
+    pub fn profile(&self, pid: u16) -> String {
+        self.profiles
+            .iter()
+            .find(|(key, _)| parse_pid(key) == Some(pid))
+            .map(|(_, name)| name.clone())
+            .unwrap_or_else(|| format!("snappy-{:04x}", pid))
+    }
+}
+
+// "0x5508" or "21768"
+pub fn parse_pid(key: &str) -> Option<u16> {
+    let key = key.trim();
+    match key.strip_prefix("0x").or_else(|| key.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => key.parse().ok(),
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ScriptingConfig {
+    // Run *.rhai scripts on every decoded frame
+    pub enabled: bool,
+    pub dir: Option<PathBuf>,
+    // How often the directory is checked for changed scripts
+    pub reload_interval_secs: u64,
+    // Limits per call, so a runaway script cannot stall the device loop
+    pub max_operations: u64,
+    pub max_call_levels: usize,
+    pub max_string_size: usize,
+    pub max_array_size: usize,
+    pub max_map_size: usize,
+}
+
+impl Default for ScriptingConfig {
+    fn default() -> Self {
+        ScriptingConfig {
+            enabled: false,
+            dir: None,
+            reload_interval_secs: 2,
+            max_operations: 100_000,
+            max_call_levels: 32,
+            max_string_size: 64 * 1024,
+            max_array_size: 10_000,
+            max_map_size: 1_000,
+        }
+    }
+}
+
+impl ScriptingConfig {
+    pub fn dir(&self) -> PathBuf {
+        self.dir.clone().unwrap_or_else(|| paths::config_dir().join("scripts"))
+    }
+}
+
+// A [[rules]] entry: fire `name` when the value is above and/or below the
+// thresholds for at least `for_ms`
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct TriggerRule {
+    pub name: String,
+    // Any peripheral when absent
+    pub mac: Option<String>,
+    pub above: Option<f64>,
+    pub below: Option<f64>,
+    pub for_ms: u64,
+    // POSTed the trigger event as JSON
+    pub webhook: Option<String>,
+    // Line written to the dongle
+    pub command: Option<String>,
+}
+
+// Signal processing per peripheral; a MAC rule replaces the default
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ProcessingConfig {
+    pub default: Option<ProcessingRule>,
+    pub mac: HashMap<String, ProcessingRule>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ProcessingRule {
+    // Average over this many values; 0 or 1 disables it
+    pub moving_average: usize,
+    // Exponential low-pass factor in (0, 1]; smaller is smoother
+    pub low_pass_alpha: Option<f64>,
+    // Drop events repeating the previous raw value
+    pub debounce: bool,
+    // Send `snappy-change` when the value moves this far from the last one reported
+    pub change_threshold: Option<f64>,
+    // Send `snappy-peak` when the value rises above this and falls back below
+    // it minus the hysteresis
+    pub peak_threshold: Option<f64>,
+    pub peak_hysteresis: f64,
+}
+
+// Calibration rules; a MAC rule wins over a PID rule, which wins over the default
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct TransformConfig {
+    pub default: Option<TransformRule>,
+    // Keyed by dongle PID, "0x5508" or decimal
+    pub pid: HashMap<String, TransformRule>,
+    pub mac: HashMap<String, TransformRule>,
+}
+
+// calibrated = clamp(value * scale + offset, min, max)
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TransformRule {
+    // Read the 16-bit value as two's complement
+    pub signed: bool,
+    pub scale: f64,
+    pub offset: f64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub unit: Option<String>,
+}
+
+impl Default for TransformRule {
+    fn default() -> Self {
+        TransformRule {
+            signed: false,
+            scale: 1.0,
+            offset: 0.0,
+            min: None,
+            max: None,
+            unit: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PeripheralsConfig {
+    // A peripheral silent for this long is reported as lost
+    pub lost_timeout_secs: u64,
+    // How often the registry is written to disk
+    pub save_interval_secs: u64,
+}
+
+impl Default for PeripheralsConfig {
+    fn default() -> Self {
+        PeripheralsConfig {
+            lost_timeout_secs: 10,
+            save_interval_secs: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct StreamConfig {
+    // How many recent events are kept for clients resuming after a disconnect
+    pub replay_buffer: usize,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        StreamConfig { replay_buffer: 1000 }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct StoreConfig {
+    // Record every snap data event in a local SQLite database
+    pub enabled: bool,
+    pub path: Option<PathBuf>,
+    // Records older than this many days are deleted; 0 keeps them forever
+    pub retention_days: u32,
+    // Oldest records are deleted beyond this count; 0 means no limit
+    pub max_records: u64,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            enabled: false,
+            path: None,
+            retention_days: 30,
+            max_records: 0,
+        }
+    }
+}
+
+impl StoreConfig {
+    pub fn path(&self) -> PathBuf {
+        self.path.clone().unwrap_or_else(|| paths::data_dir().join("history.sqlite3"))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct PrivilegesConfig {
+    // When started as root, switch to this user once the listeners are bound
+    pub user: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct InstanceConfig {
+    pub on_conflict: OnConflict,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ServerConfig {
+    // Addresses the HTTP/Socket.IO listener binds to. Loopback only unless a
+    // deployment explicitly needs LAN access (e.g. "0.0.0.0" or "::").
+    pub listen: Vec<IpAddr>,
+    // First port tried and how many consecutive ports to try after it
+    pub port: u16,
+    pub port_attempts: u16,
+    // Fixed port serving only /discovery, outside the range above; 0 disables it
+    pub discovery_port: u16,
+    // Optional Unix domain socket for local native tools
+    pub unix_socket: Option<PathBuf>,
+    // Group allowed to connect to the Unix socket besides its owner
+    pub unix_socket_group: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: vec![IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)],
+            port: 8436,
+            port_attempts: 10,
+            discovery_port: 8435,
+            unix_socket: None,
+            unix_socket_group: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    // First port tried for the HTTPS/WSS listener
+    pub port: u16,
+    // Where the generated CA and localhost certificate are kept
+    pub cert_dir: Option<PathBuf>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            enabled: false,
+            port: 8446,
+            cert_dir: None,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn cert_dir(&self) -> PathBuf {
+        self.cert_dir.clone().unwrap_or_else(|| paths::data_dir().join("tls"))
+    }
+}
+
+pub fn default_config_path() -> PathBuf {
+    paths::config_dir().join("config.toml")
+}
+
+fn load_from_file(path: &Path) -> Result<AgentConfig, Box<dyn std::error::Error>> {
+    let content = fs::read_to_string(path)?;
+    Ok(toml::from_str(&content)?)
+}
+
+// Load the configuration once at startup; command line flags win over the file
+pub fn init(args: &Args) -> &'static AgentConfig {
+    CONFIG.get_or_init(|| {
+        let path = args.config
+            .clone()
+            .or_else(|| std::env::var_os("SNAPPY_WEB_AGENT_CONFIG").map(PathBuf::from))
+            .unwrap_or_else(default_config_path);
+
+        let mut config = if path.exists() {
+            match load_from_file(&path) {
+                Ok(config) => {
+                    info!("Loaded configuration from {}", path.display());
+                    config
+                }
+                // Defaults would silently drop [privileges], TLS and listen settings
+                Err(e) => panic!("Failed to load configuration from {}: {}", path.display(), e),
+            }
+        } else {
+            AgentConfig::default()
+        };
+
+        if !args.listen.is_empty() {
+            config.server.listen = args.listen.clone();
+        }
+        if let Some(path) = &args.unix_socket {
+            config.server.unix_socket = Some(path.clone());
+        }
+        if args.replace {
+            config.instance.on_conflict = OnConflict::Replace;
+        }
+        if let Some(user) = &args.user {
+            config.privileges.user = Some(user.clone());
+        }
+        if args.tls {
+            config.tls.enabled = true;
+        }
+
+        config
+    })
+}
+
+pub fn get() -> &'static AgentConfig {
+    CONFIG.get_or_init(AgentConfig::default)
+}
@@ -0,0 +1,356 @@
+use std::collections::{ HashMap, VecDeque };
+use std::sync::{ Mutex, MutexGuard, OnceLock };
+use std::time::{ Duration, Instant };
+use socketioxide::{ extract::SocketRef, socket::Sid, SendError };
+use tokio_util::sync::CancellationToken;
+use crate::{ encoding, models::*, shutdown };
+
+// How often a rate-limited client without batching is served
+const RATE_TICK: Duration = Duration::from_millis(50);
+const MAX_QUEUE: usize = 100_000;
+// Shorter batches would just be per-event delivery with extra timer wakeups
+const MIN_BATCH_MS: u64 = 10;
+
+// Anything sent to a client per event; all of it shares the client's queue and rate
+pub enum Outgoing {
+    Data(SnapDataEvent),
+    Change(SnapChangeEvent),
+    Peak(SnapPeakEvent),
+    // Custom event from a script, already prefixed
+    Script(String, serde_json::Value),
+}
+
+impl Outgoing {
+    // Data in the client's encoding; changes and peaks are always JSON
+    fn emit(&self, socket: &SocketRef) -> Result<(), SendError> {
+        match self {
+            Outgoing::Data(event) => encoding::emit_data(socket, event),
+            Outgoing::Change(change) => socket.emit("snappy-change", change),
+            Outgoing::Peak(peak) => socket.emit("snappy-peak", peak),
+            Outgoing::Script(event, data) => socket.emit(event.clone(), data),
+        }
+    }
+}
+
+// Events waiting for a client that asked for batching or a rate limit
+struct Queue {
+    options: DeliveryOptions,
+    events: VecDeque<Outgoing>,
+    // Token bucket for max_rate
+    tokens: f64,
+    refilled: Instant,
+    // Stops the client's flusher
+    flusher: CancellationToken,
+}
+
+impl Queue {
+    fn new(options: DeliveryOptions, events: VecDeque<Outgoing>, flusher: CancellationToken) -> Self {
+        Queue {
+            tokens: options.max_rate as f64,
+            refilled: Instant::now(),
+            options,
+            events,
+            flusher,
+        }
+    }
+
+    // Drop-oldest: a slow client gets the most recent data. True if an event was dropped.
+    fn push(&mut self, event: Outgoing) -> bool {
+        let dropped = self.events.len() >= self.options.max_queue;
+        if dropped {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+        dropped
+    }
+
+    // How many queued events may go out at `now`; takes their tokens from the bucket
+    fn allowance(&mut self, now: Instant) -> usize {
+        let count = self.events.len();
+        if self.options.max_rate == 0 {
+            return count;
+        }
+        let rate = self.options.max_rate as f64;
+        self.tokens = (self.tokens + now.saturating_duration_since(self.refilled).as_secs_f64() * rate).min(rate);
+        self.refilled = now;
+        let count = count.min(self.tokens as usize);
+        self.tokens -= count as f64;
+        count
+    }
+}
+
+#[derive(Default)]
+struct Client {
+    queue: Option<Queue>,
+    delivered: u64,
+    dropped: u64,
+    // `dropped` at the last snappy-dropped notice
+    reported: u64,
+}
+
+static CLIENTS: OnceLock<Mutex<HashMap<Sid, Client>>> = OnceLock::new();
+
+fn clients() -> MutexGuard<'static, HashMap<Sid, Client>> {
+    CLIENTS.get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+// Hand an event to a client: straight away, or through its queue
+pub fn send(socket: &SocketRef, event: Outgoing) {
+    let mut clients = clients();
+    let client = clients.entry(socket.id).or_default();
+    match client.queue.as_mut() {
+        Some(queue) => {
+            if queue.push(event) {
+                client.dropped += 1;
+            }
+        }
+        // Socket.IO refuses events once the client's send buffer is full
+        // The notice has to wait until the buffer has room again
+        None =>
+            match event.emit(socket) {
+                Ok(()) => {
+                    client.delivered += 1;
+                    report_dropped(socket, client);
+                }
+                Err(_) => {
+                    client.dropped += 1;
+                }
+            }
+    }
+}
+
+// Tell the client how many events it lost since the last notice
+fn report_dropped(socket: &SocketRef, client: &mut Client) {
+    if client.dropped > client.reported {
+        let notice = DroppedEvent { dropped: client.dropped - client.reported, total: client.dropped };
+        if socket.emit("snappy-dropped", &notice).is_ok() {
+            client.reported = client.dropped;
+        }
+    }
+}
+
+fn validate(options: &DeliveryOptions) -> Result<(), String> {
+    if options.max_queue == 0 || options.max_queue > MAX_QUEUE {
+        return Err(format!("max_queue must be between 1 and {}", MAX_QUEUE));
+    }
+    if options.batch_ms > 0 && options.batch_ms < MIN_BATCH_MS {
+        return Err(format!("batch_ms must be 0 (no batching) or at least {}", MIN_BATCH_MS));
+    }
+    Ok(())
+}
+
+pub fn configure(socket: &SocketRef, options: DeliveryOptions) -> Result<(), String> {
+    validate(&options)?;
+    let mut clients = clients();
+    let client = clients.entry(socket.id).or_default();
+    // Events queued under the old options are kept
+    let mut events = VecDeque::new();
+    if let Some(previous) = client.queue.take() {
+        previous.flusher.cancel();
+        events = previous.events;
+    }
+    // Without batching or a rate limit, events go out directly
+    if options.batch_ms == 0 && options.max_rate == 0 {
+        for event in events {
+            match event.emit(socket) {
+                Ok(()) => {
+                    client.delivered += 1;
+                }
+                Err(_) => {
+                    client.dropped += 1;
+                }
+            }
+        }
+        report_dropped(socket, client);
+        return Ok(());
+    }
+    while events.len() > options.max_queue {
+        events.pop_front();
+        client.dropped += 1;
+    }
+
+    let flusher = shutdown::token().child_token();
+    client.queue = Some(Queue::new(options.clone(), events, flusher.clone()));
+
+    let period = if options.batch_ms > 0 { Duration::from_millis(options.batch_ms) } else { RATE_TICK };
+    let socket = socket.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = flusher.cancelled() => break,
+            }
+            flush(&socket);
+        }
+    });
+    Ok(())
+}
+
+// Send what the client's rate allows, as one batch or as single events
+fn flush(socket: &SocketRef) {
+    let mut clients = clients();
+    let Some(client) = clients.get_mut(&socket.id) else {
+        return;
+    };
+    let Some(queue) = client.queue.as_mut() else {
+        return;
+    };
+
+    let count = queue.allowance(Instant::now());
+    let mut events: Vec<Outgoing> = queue.events.drain(..count).collect();
+
+    // Data goes out as one batch, changes and peaks after it one by one
+    if queue.options.batch_ms > 0 {
+        let mut data = Vec::new();
+        let mut signals = Vec::new();
+        for event in events {
+            match event {
+                Outgoing::Data(snap_data) => data.push(snap_data),
+                other => signals.push(other),
+            }
+        }
+        events = signals;
+        if !data.is_empty() {
+            let batch = SnapBatchEvent { events: data, dropped: client.dropped };
+            match encoding::emit_batch(socket, &batch) {
+                Ok(()) => {
+                    client.delivered += batch.events.len() as u64;
+                }
+                Err(_) => {
+                    client.dropped += batch.events.len() as u64;
+                }
+            }
+        }
+    }
+    for event in &events {
+        match event.emit(socket) {
+            Ok(()) => {
+                client.delivered += 1;
+            }
+            Err(_) => {
+                client.dropped += 1;
+            }
+        }
+    }
+
+    report_dropped(socket, client);
+}
+
+pub fn stats(sid: Sid) -> DeliveryStats {
+    let clients = clients();
+    let client = clients.get(&sid);
+    DeliveryStats {
+        options: client
+            .and_then(|client| client.queue.as_ref())
+            .map(|queue| queue.options.clone())
+            .unwrap_or_default(),
+        delivered: client.map_or(0, |client| client.delivered),
+        dropped: client.map_or(0, |client| client.dropped),
+        queued: client.and_then(|client| client.queue.as_ref()).map_or(0, |queue| queue.events.len()),
+    }
+}
+
+pub fn remove(sid: Sid) {
+    if let Some(queue) = clients().remove(&sid).and_then(|client| client.queue) {
+        queue.flusher.cancel();
+    }
+}
+
+pub fn response(command: &str, sid: Sid, result: Result<(), String>) -> DeliveryResponse {
+    match result {
+        Ok(()) =>
+            DeliveryResponse {
+                success: true,
+                command: command.to_string(),
+                stats: Some(stats(sid)),
+                error: None,
+            },
+        Err(e) =>
+            DeliveryResponse {
+                success: false,
+                command: command.to_string(),
+                stats: None,
+                error: Some(e),
+            },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(max_rate: u32, max_queue: usize, queued: u16) -> Queue {
+        let options = DeliveryOptions { batch_ms: 0, max_rate, max_queue };
+        let events = (0..queued).map(|value| Outgoing::Data(SnapDataEvent::raw("aa:bb:cc:dd:ee:ff", value, ""))).collect();
+        Queue::new(options, events, CancellationToken::new())
+    }
+
+    fn values(queue: &Queue) -> Vec<u16> {
+        queue.events
+            .iter()
+            .map(|event| match event {
+                Outgoing::Data(event) => event.value,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn without_a_rate_everything_goes_out() {
+        let mut queue = queue(0, 1000, 500);
+        assert_eq!(queue.allowance(Instant::now()), 500);
+    }
+
+    #[test]
+    fn bucket_starts_full_and_refills_at_the_rate() {
+        let mut queue = queue(10, 1000, 100);
+        let start = queue.refilled;
+        assert_eq!(queue.allowance(start), 10);
+        assert_eq!(queue.allowance(start), 0);
+        assert_eq!(queue.allowance(start + Duration::from_millis(500)), 5);
+        assert_eq!(queue.allowance(start + Duration::from_millis(550)), 0);
+        assert_eq!(queue.allowance(start + Duration::from_millis(650)), 1);
+    }
+
+    #[test]
+    fn bucket_holds_at_most_one_second_of_tokens() {
+        let mut queue = queue(10, 1000, 100);
+        let start = queue.refilled;
+        assert_eq!(queue.allowance(start + Duration::from_secs(60)), 10);
+    }
+
+    #[test]
+    fn unused_tokens_are_kept() {
+        let mut queue = queue(10, 1000, 3);
+        let start = queue.refilled;
+        assert_eq!(queue.allowance(start), 3);
+        queue.events.drain(..3);
+        queue.push(Outgoing::Data(SnapDataEvent::raw("aa:bb:cc:dd:ee:ff", 0, "")));
+        assert_eq!(queue.allowance(start), 1);
+        assert_eq!(queue.tokens, 6.0);
+    }
+
+    #[test]
+    fn full_queue_drops_the_oldest() {
+        let mut queue = queue(0, 3, 3);
+        assert!(queue.push(Outgoing::Data(SnapDataEvent::raw("aa:bb:cc:dd:ee:ff", 3, ""))));
+        assert_eq!(values(&queue), [1, 2, 3]);
+        queue.events.pop_front();
+        assert!(!queue.push(Outgoing::Data(SnapDataEvent::raw("aa:bb:cc:dd:ee:ff", 4, ""))));
+        assert_eq!(values(&queue), [2, 3, 4]);
+    }
+
+    #[test]
+    fn options_are_validated() {
+        let options = |batch_ms, max_queue| DeliveryOptions { batch_ms, max_rate: 0, max_queue };
+        assert!(validate(&options(0, 1)).is_ok());
+        assert!(validate(&options(MIN_BATCH_MS, MAX_QUEUE)).is_ok());
+        assert!(validate(&options(MIN_BATCH_MS - 1, 1)).is_err());
+        assert!(validate(&options(0, 0)).is_err());
+        assert!(validate(&options(0, MAX_QUEUE + 1)).is_err());
+    }
+}
@@ -0,0 +1,51 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use tracing::info;
+use crate::{ models::DiscoveryInfo, paths };
+
+const DISCOVERY_FILE: &str = "agent.json";
+
+static DISCOVERY: OnceLock<DiscoveryInfo> = OnceLock::new();
+
+pub fn discovery_path() -> PathBuf {
+    paths::runtime_dir().join(DISCOVERY_FILE)
+}
+
+pub fn current() -> Option<&'static DiscoveryInfo> {
+    DISCOVERY.get()
+}
+
+// Record where this agent is listening and advertise it in the runtime directory
+pub fn publish(info: DiscoveryInfo) -> Result<(), Box<dyn std::error::Error>> {
+    let info = DISCOVERY.get_or_init(|| info);
+
+    let path = discovery_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Write next to the target and rename so readers never see a partial file
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_vec_pretty(info)?)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o644))?;
+    }
+    fs::rename(&tmp_path, &path)?;
+
+    info!("Published discovery file {}", path.display());
+    Ok(())
+}
+
+// Stop advertising this agent; only removes the file if it still describes us
+pub fn withdraw() {
+    let path = discovery_path();
+    let ours = fs::read(&path)
+        .ok()
+        .and_then(|content| serde_json::from_slice::<DiscoveryInfo>(&content).ok())
+        .is_some_and(|info| info.pid == std::process::id());
+    if ours {
+        let _ = fs::remove_file(&path);
+    }
+}
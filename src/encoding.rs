@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::sync::{ Mutex, MutexGuard, OnceLock };
+use bytes::{ BufMut, Bytes, BytesMut };
+use chrono::DateTime;
+use serde::Serialize;
+use socketioxide::{ extract::SocketRef, socket::Sid, SendError };
+use crate::models::*;
+
+// First byte of every binary payload, bumped if the record layout changes
+const FORMAT_VERSION: u8 = 1;
+// seq u64, timestamp_ms i64, mac [u8; 6], value u16, pid u16, calibrated f64, filtered f64
+pub const RECORD_SIZE: usize = 42;
+
+// Clients that opted out of JSON
+static ENCODINGS: OnceLock<Mutex<HashMap<Sid, Encoding>>> = OnceLock::new();
+
+fn encodings() -> MutexGuard<'static, HashMap<Sid, Encoding>> {
+    ENCODINGS.get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn of(sid: Sid) -> Encoding {
+    encodings().get(&sid).copied().unwrap_or_default()
+}
+
+pub fn set(sid: Sid, encoding: Encoding) {
+    match encoding {
+        Encoding::Json => encodings().remove(&sid),
+        Encoding::Binary => encodings().insert(sid, encoding),
+    };
+}
+
+pub fn remove(sid: Sid) {
+    encodings().remove(&sid);
+}
+
+fn put_record(buffer: &mut BytesMut, event: &SnapDataEvent) {
+    let timestamp_ms = DateTime::parse_from_rfc3339(&event.timestamp).map_or(0, |time| time.timestamp_millis());
+    let mut mac = [0u8; 6];
+    for (byte, part) in mac.iter_mut().zip(event.mac.split(':')) {
+        *byte = u8::from_str_radix(part, 16).unwrap_or(0);
+    }
+    buffer.put_u64_le(event.seq);
+    buffer.put_i64_le(timestamp_ms);
+    buffer.put_slice(&mac);
+    buffer.put_u16_le(event.value);
+    buffer.put_u16_le(event.pid);
+    // NaN stands for null
+    buffer.put_f64_le(event.calibrated.unwrap_or(f64::NAN));
+    buffer.put_f64_le(event.filtered.unwrap_or(f64::NAN));
+}
+
+// Version byte followed by one record per event; `unit` and `extra` are left out
+pub fn pack(events: &[SnapDataEvent]) -> Bytes {
+    let mut buffer = BytesMut::with_capacity(1 + events.len() * RECORD_SIZE);
+    buffer.put_u8(FORMAT_VERSION);
+    for event in events {
+        put_record(&mut buffer, event);
+    }
+    buffer.freeze()
+}
+
+#[derive(Serialize)]
+struct BinaryBatch {
+    events: Bytes,
+    dropped: u64,
+}
+
+// `snappy-data` in the client's encoding
+pub fn emit_data(socket: &SocketRef, event: &SnapDataEvent) -> Result<(), SendError> {
+    match of(socket.id) {
+        Encoding::Json => socket.emit("snappy-data", event),
+        Encoding::Binary => socket.emit("snappy-data", &pack(std::slice::from_ref(event))),
+    }
+}
+
+// `snappy-batch` in the client's encoding
+pub fn emit_batch(socket: &SocketRef, batch: &SnapBatchEvent) -> Result<(), SendError> {
+    match of(socket.id) {
+        Encoding::Json => socket.emit("snappy-batch", batch),
+        Encoding::Binary => socket.emit("snappy-batch", &BinaryBatch { events: pack(&batch.events), dropped: batch.dropped }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> SnapDataEvent {
+        let mut event = SnapDataEvent::raw("aa:bb:cc:dd:ee:0f", 0x1234, "2025-01-01T00:00:01.5Z");
+        event.seq = 7;
+        event.calibrated = Some(2.5);
+        event
+    }
+
+    fn f64_at(bytes: &[u8], offset: usize) -> f64 {
+        f64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn pack_starts_with_the_version() {
+        assert_eq!(&pack(&[])[..], [FORMAT_VERSION]);
+    }
+
+    #[test]
+    fn pack_lays_out_one_record_per_event() {
+        let packed = pack(&[event(), event()]);
+        assert_eq!(packed.len(), 1 + 2 * RECORD_SIZE);
+
+        let record = &packed[1..1 + RECORD_SIZE];
+        assert_eq!(u64::from_le_bytes(record[0..8].try_into().unwrap()), 7);
+        assert_eq!(i64::from_le_bytes(record[8..16].try_into().unwrap()), 1_735_689_601_500);
+        assert_eq!(record[16..22], [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x0f]);
+        assert_eq!(u16::from_le_bytes(record[22..24].try_into().unwrap()), 0x1234);
+        assert_eq!(u16::from_le_bytes(record[24..26].try_into().unwrap()), PID);
+        assert_eq!(f64_at(record, 26), 2.5);
+        assert!(f64_at(record, 34).is_nan());
+        assert_eq!(&packed[1..1 + RECORD_SIZE], &packed[1 + RECORD_SIZE..]);
+    }
+
+    #[test]
+    fn unparsable_fields_pack_as_zero() {
+        let mut event = event();
+        event.timestamp = "yesterday".to_string();
+        event.mac = "zz:bb".to_string();
+        let packed = pack(&[event]);
+        assert_eq!(i64::from_le_bytes(packed[9..17].try_into().unwrap()), 0);
+        assert_eq!(packed[17..23], [0, 0xbb, 0, 0, 0, 0]);
+    }
+}
@@ -4,14 +4,14 @@ fn load_key_from_cargo_toml() -> Result<[u32; 8], Box<dyn std::error::Error>> {
     let cargo_content = fs::read_to_string("Cargo.toml")?;
     let cargo_toml: CargoToml = toml::from_str(&cargo_content)?;
 
-    if let Some(metadata) = cargo_toml.package.metadata {
-        if let Some(encryption) = metadata.encryption {
-            if encryption.key.len() == 8 {
-                let mut key_array = [0u32; 8];
-                key_array.copy_from_slice(&encryption.key);
-                return Ok(key_array);
-            }
-        }
+    if
+        let Some(metadata) = cargo_toml.package.metadata &&
+        let Some(encryption) = metadata.encryption &&
+        encryption.key.len() == 8
+    {
+        let mut key_array = [0u32; 8];
+        key_array.copy_from_slice(&encryption.key);
+        return Ok(key_array);
     }
 
     // Fallback to default key if not found in Cargo.toml
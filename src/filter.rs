@@ -0,0 +1,169 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{ OnceLock, RwLock };
+use tracing::info;
+use crate::{ config, models::*, paths };
+
+struct Lists {
+    allow: HashSet<String>,
+    deny: HashSet<String>,
+}
+
+impl Lists {
+    // Denied MACs never pass; a non-empty allowlist lets only its MACs through
+    fn allows(&self, mac: &str) -> bool {
+        !self.deny.contains(mac) && (self.allow.is_empty() || self.allow.contains(mac))
+    }
+}
+
+// Agent-wide allow/deny lists, applied to every frame before it is emitted
+static LISTS: OnceLock<RwLock<Lists>> = OnceLock::new();
+
+fn lists() -> &'static RwLock<Lists> {
+    LISTS.get_or_init(|| {
+        // Lists edited at runtime win over the config file
+        let saved: Option<MacFilterLists> = std::fs
+            ::read(filter_path())
+            .ok()
+            .and_then(|content| serde_json::from_slice(&content).ok());
+        let source = saved.unwrap_or_else(|| config::get().filter.clone());
+        RwLock::new(Lists {
+            allow: source.allow.iter().filter_map(|mac| parse_mac(mac)).collect(),
+            deny: source.deny.iter().filter_map(|mac| parse_mac(mac)).collect(),
+        })
+    })
+}
+
+fn filter_path() -> PathBuf {
+    paths::data_dir().join("mac-filter.json")
+}
+
+// "AA-BB-CC-DD-EE-FF" and similar spellings to the "aa:bb:cc:dd:ee:ff" frames use
+pub fn parse_mac(mac: &str) -> Option<String> {
+    let mac = mac.trim().to_lowercase().replace('-', ":");
+    let parts: Vec<&str> = mac.split(':').collect();
+    let valid = parts.len() == 6 && parts.iter().all(|part| part.len() == 2 && part.chars().all(|c| c.is_ascii_hexdigit()));
+    valid.then_some(mac)
+}
+
+pub fn parse_macs(macs: &[String]) -> Result<HashSet<String>, String> {
+    macs.iter()
+        .map(|mac| parse_mac(mac).ok_or_else(|| format!("invalid MAC address {:?}", mac)))
+        .collect()
+}
+
+pub fn allows(mac: &str) -> bool {
+    lists()
+        .read()
+        .map_or(true, |lists| lists.allows(mac))
+}
+
+fn sorted(macs: &HashSet<String>) -> Vec<String> {
+    let mut macs: Vec<String> = macs.iter().cloned().collect();
+    macs.sort();
+    macs
+}
+
+pub fn current() -> MacFilterLists {
+    lists()
+        .read()
+        .map(|lists| MacFilterLists { allow: sorted(&lists.allow), deny: sorted(&lists.deny) })
+        .unwrap_or_default()
+}
+
+// Replace whichever lists the update names and remember them across restarts
+pub fn update(update: MacFilterUpdate) -> Result<MacFilterLists, String> {
+    let allow = update.allow.as_deref().map(parse_macs).transpose()?;
+    let deny = update.deny.as_deref().map(parse_macs).transpose()?;
+    {
+        let mut lists = lists().write().map_err(|_| "MAC filter is unavailable")?;
+        if let Some(allow) = allow {
+            lists.allow = allow;
+        }
+        if let Some(deny) = deny {
+            lists.deny = deny;
+        }
+    }
+
+    let current = current();
+    info!("MAC filter updated: allow {:?}, deny {:?}", current.allow, current.deny);
+    let path = filter_path();
+    let saved = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(&path, serde_json::to_vec_pretty(&current)?));
+    if let Err(e) = saved {
+        info!("Failed to save MAC filter {}: {}", path.display(), e);
+    }
+    Ok(current)
+}
+
+pub fn response(command: &str, result: Result<MacFilterLists, String>) -> MacFilterResponse {
+    match result {
+        Ok(filter) =>
+            MacFilterResponse {
+                success: true,
+                command: command.to_string(),
+                filter: Some(filter),
+                error: None,
+            },
+        Err(e) =>
+            MacFilterResponse {
+                success: false,
+                command: command.to_string(),
+                filter: None,
+                error: Some(e),
+            },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lists(allow: &[&str], deny: &[&str]) -> Lists {
+        Lists {
+            allow: allow.iter().map(|mac| mac.to_string()).collect(),
+            deny: deny.iter().map(|mac| mac.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn parse_mac_normalizes_spellings() {
+        assert_eq!(parse_mac("AA-BB-CC-DD-EE-FF").as_deref(), Some("aa:bb:cc:dd:ee:ff"));
+        assert_eq!(parse_mac(" aa:bb:cc:dd:ee:0f ").as_deref(), Some("aa:bb:cc:dd:ee:0f"));
+    }
+
+    #[test]
+    fn parse_mac_rejects_malformed_addresses() {
+        for mac in ["", "aa:bb:cc:dd:ee", "aa:bb:cc:dd:ee:ff:00", "a:bb:cc:dd:ee:fff", "gg:bb:cc:dd:ee:ff", "aabbccddeeff"] {
+            assert_eq!(parse_mac(mac), None, "{:?}", mac);
+        }
+    }
+
+    #[test]
+    fn parse_macs_names_the_invalid_one() {
+        let macs = ["aa:bb:cc:dd:ee:ff".to_string(), "nope".to_string()];
+        assert_eq!(parse_macs(&macs), Err("invalid MAC address \"nope\"".to_string()));
+        assert_eq!(parse_macs(&macs[..1]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn empty_lists_allow_everything() {
+        assert!(lists(&[], &[]).allows("aa:bb:cc:dd:ee:ff"));
+    }
+
+    #[test]
+    fn allowlist_lets_only_its_macs_through() {
+        let lists = lists(&["aa:bb:cc:dd:ee:ff"], &[]);
+        assert!(lists.allows("aa:bb:cc:dd:ee:ff"));
+        assert!(!lists.allows("11:22:33:44:55:66"));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let lists = lists(&["aa:bb:cc:dd:ee:ff"], &["aa:bb:cc:dd:ee:ff", "11:22:33:44:55:66"]);
+        assert!(!lists.allows("aa:bb:cc:dd:ee:ff"));
+        assert!(!lists.allows("11:22:33:44:55:66"));
+    }
+}
@@ -0,0 +1,305 @@
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ self, Read, Seek, SeekFrom, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::OnceLock;
+use std::time::Duration;
+use serde::Deserialize;
+use tracing::info;
+use crate::{ config, discovery, models::DiscoveryInfo, paths };
+
+const LOCK_FILE: &str = "snappy-web-agent.lock";
+// Secret that authorises POST /shutdown; readable only by the agent's user and root
+const SHUTDOWN_TOKEN_FILE: &str = "shutdown.token";
+const SHUTDOWN_REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+
+static SHUTDOWN_TOKEN: OnceLock<String> = OnceLock::new();
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What to do when another agent already holds the instance lock
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnConflict {
+    // Report the running agent and exit successfully
+    #[default]
+    Exit,
+    // Ask the running agent to shut down and take over once it has
+    Replace,
+}
+
+// Held for the lifetime of the agent; the OS releases the lock when the
+// process exits, even if it crashes
+pub struct InstanceGuard {
+    file: File,
+}
+
+impl InstanceGuard {
+    // Store our location in the lock file so a second instance can report it
+    pub fn record(&mut self, info: &DiscoveryInfo) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&serde_json::to_vec(info)?)?;
+        self.file.flush()
+    }
+}
+
+// Generate the shutdown token and store it in the runtime directory, mode 0600
+pub fn issue_shutdown_token() -> io::Result<()> {
+    use ring::rand::SecureRandom;
+    let mut bytes = [0u8; 32];
+    ring::rand::SystemRandom::new().fill(&mut bytes).map_err(|_| io::Error::other("no system randomness"))?;
+    let token: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    let path = paths::runtime_dir().join(SHUTDOWN_TOKEN_FILE);
+    fs::create_dir_all(paths::runtime_dir())?;
+    let _ = fs::remove_file(&path);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&path)?.write_all(token.as_bytes())?;
+    let _ = SHUTDOWN_TOKEN.set(token);
+    Ok(())
+}
+
+pub fn shutdown_token_matches(token: &str) -> bool {
+    SHUTDOWN_TOKEN.get().is_some_and(|expected| expected == token)
+}
+
+// Ask the running agent to stop through its own endpoint. Only works for a user
+// who can read its token: the same user, or root for the system service.
+async fn request_shutdown_over_http(port: u16) -> bool {
+    let Ok(token) = fs::read_to_string(paths::runtime_dir().join(SHUTDOWN_TOKEN_FILE)) else {
+        info!("Cannot read the running agent's shutdown token");
+        return false;
+    };
+    let result = tokio::task::spawn_blocking(move || {
+        let agent = ureq::Agent
+            ::config_builder()
+            .timeout_global(Some(SHUTDOWN_REQUEST_TIMEOUT))
+            .build()
+            .new_agent();
+        agent
+            .post(format!("http://127.0.0.1:{}/shutdown", port))
+            .header("X-Shutdown-Token", token.trim())
+            .send_empty()
+    }).await;
+    match result {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+            info!("Running agent refused the shutdown request: {}", e);
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+pub fn lock_path() -> PathBuf {
+    paths::shared_lock_dir().join(LOCK_FILE)
+}
+
+fn open_lock_file(path: &Path) -> io::Result<File> {
+    // Open an existing file without O_CREAT first: sticky directories with
+    // protected_regular refuse O_CREAT on files owned by other users
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => {
+            return Ok(file);
+        }
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            // Locking only needs a readable handle, which only the agent's group gets
+            return OpenOptions::new().read(true).open(path);
+        }
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            return Err(e);
+        }
+        Err(_) => {}
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(false);
+    // Anyone who can open the lock can hold it and keep the agent from starting,
+    // so only the agent's user and group get access
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o640);
+    }
+    let file = options.open(path)?;
+    // Created by root before dropping privileges: hand it to the service user
+    #[cfg(unix)]
+    if
+        paths::is_root() &&
+        let Some(user) = &config::get().privileges.user &&
+        let Some(account) = crate::privileges::lookup_user(user)
+    {
+        std::os::unix::fs::chown(path, Some(account.uid), Some(account.gid))?;
+    }
+    Ok(file)
+}
+
+// The shared lock lives where only root can create files and only the agent's
+// group can open it. Other users (or everyone, before a service created it) fall
+// back to a lock of their own.
+pub fn open_shared_lock() -> io::Result<(File, PathBuf)> {
+    let path = lock_path();
+    match open_lock_file(&path) {
+        Ok(file) => Ok((file, path)),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            let fallback = paths::runtime_dir().join(LOCK_FILE);
+            info!(
+                "Cannot open {} ({}); using {}, which does not guard against agents of other users",
+                path.display(),
+                e,
+                fallback.display()
+            );
+            Ok((open_lock_file(&fallback)?, fallback))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Best-effort description of the agent holding the lock
+fn running_instance(file: &mut File) -> Option<DiscoveryInfo> {
+    let mut content = String::new();
+    if
+        file.seek(SeekFrom::Start(0)).is_ok() &&
+        file.read_to_string(&mut content).is_ok() &&
+        let Ok(info) = serde_json::from_str(&content)
+    {
+        return Some(info);
+    }
+    // Windows locks block reads; the discovery file lives in a shared directory there
+    fs::read(discovery::discovery_path())
+        .ok()
+        .and_then(|content| serde_json::from_slice(&content).ok())
+}
+
+#[cfg(target_os = "linux")]
+fn executable_of(pid: u32) -> Option<PathBuf> {
+    let exe = fs::read_link(format!("/proc/{}/exe", pid)).ok()?;
+    // A binary replaced by an upgrade shows up as "<path> (deleted)"
+    Some(PathBuf::from(exe.to_string_lossy().trim_end_matches(" (deleted)")))
+}
+
+#[cfg(target_os = "macos")]
+fn executable_of(pid: u32) -> Option<PathBuf> {
+    let mut buffer = vec![0u8; libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+    // SAFETY: the buffer is writable for the stated size
+    let len = unsafe { libc::proc_pidpath(pid as libc::c_int, buffer.as_mut_ptr().cast(), buffer.len() as u32) };
+    if len <= 0 {
+        return None;
+    }
+    buffer.truncate(len as usize);
+    String::from_utf8(buffer).ok().map(PathBuf::from)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn executable_of(_pid: u32) -> Option<PathBuf> {
+    None
+}
+
+// Whether `pid` holds the flock on `file`, from /proc/locks lines like
+// "1: FLOCK  ADVISORY  WRITE 1234 fd:01:5678 0 EOF"
+#[cfg(target_os = "linux")]
+fn holds_lock(pid: u32, file: &File) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let Ok(inode) = file.metadata().map(|metadata| metadata.ino().to_string()) else {
+        return false;
+    };
+    let pid = pid.to_string();
+    fs::read_to_string("/proc/locks").is_ok_and(|locks| {
+        locks.lines().any(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            fields.get(1) == Some(&"FLOCK") &&
+                fields.get(4) == Some(&pid.as_str()) &&
+                fields.get(5).is_some_and(|id| id.rsplit(':').next() == Some(inode.as_str()))
+        })
+    })
+}
+
+// macOS has no lock table to ask; the executable check has to do
+#[cfg(not(target_os = "linux"))]
+fn holds_lock(_pid: u32, _file: &File) -> bool {
+    true
+}
+
+// The PID comes from a file; only signal it if it is this program and holds the lock
+fn is_agent(pid: u32, file: &File) -> bool {
+    let ours = std::env::current_exe().ok().and_then(|exe| exe.canonicalize().ok());
+    let theirs = executable_of(pid).and_then(|exe| exe.canonicalize().ok().or(Some(exe)));
+    ours.is_some() && ours == theirs && holds_lock(pid, file)
+}
+
+#[cfg(unix)]
+fn request_shutdown(pid: u32, file: &File) -> bool {
+    if !is_agent(pid, file) {
+        info!("Not signalling pid {}: cannot verify it is this agent holding the instance lock", pid);
+        return false;
+    }
+    // SAFETY: kill has no memory-safety preconditions
+    (unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) }) == 0
+}
+
+#[cfg(not(unix))]
+fn request_shutdown(_pid: u32, _file: &File) -> bool {
+    false
+}
+
+// Take the single-instance lock. Returns None when another agent is running
+// and this one should exit.
+pub async fn acquire(on_conflict: OnConflict) -> io::Result<Option<InstanceGuard>> {
+    let (mut file, path) = open_shared_lock()?;
+
+    match file.try_lock() {
+        Ok(()) => {
+            return Ok(Some(InstanceGuard { file }));
+        }
+        Err(fs::TryLockError::WouldBlock) => {}
+        Err(fs::TryLockError::Error(e)) => {
+            return Err(e);
+        }
+    }
+
+    let running = running_instance(&mut file);
+    match &running {
+        Some(running) =>
+            info!(
+                "Snappy Web Agent {} is already running (pid {}, port {})",
+                running.version,
+                running.pid,
+                running.port
+            ),
+        None => info!("Snappy Web Agent is already running (lock held on {})", path.display()),
+    }
+
+    if on_conflict == OnConflict::Exit {
+        return Ok(None);
+    }
+
+    let Some(running) = running else {
+        info!("Cannot replace the running agent: its PID is unknown");
+        return Ok(None);
+    };
+    if !request_shutdown(running.pid, &file) && !request_shutdown_over_http(running.port).await {
+        info!("Cannot replace the running agent (pid {})", running.pid);
+        return Ok(None);
+    }
+
+    info!("Asked agent pid {} to shut down, waiting to take over...", running.pid);
+    let deadline = tokio::time::Instant::now() + REPLACE_TIMEOUT;
+    while tokio::time::Instant::now() < deadline {
+        if file.try_lock().is_ok() {
+            info!("Took over from agent pid {}", running.pid);
+            return Ok(Some(InstanceGuard { file }));
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    info!("Agent pid {} did not release the lock in time", running.pid);
+    Ok(None)
+}
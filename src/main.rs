@@ -2,12 +2,33 @@ mod socketio;
 mod encryption;
 mod serial;
 mod models;
+mod cli;
+mod config;
+mod paths;
+mod tls;
+mod server;
+mod discovery;
+mod instance;
+mod shutdown;
+mod systemd;
+mod privileges;
+mod selfcheck;
+mod store;
+mod stream;
+mod sessions;
+mod peripherals;
+mod filter;
+mod transform;
+mod signal;
+mod rules;
+mod scripting;
+mod aggregate;
+mod delivery;
+mod encoding;
+mod protocol;
 
-use axum::routing::get;
-use socketioxide::SocketIo;
-use tracing::info;
 use tracing_subscriber::FmtSubscriber;
-use tower_http::cors::{ CorsLayer, Any };
+use server::start_server;
 
 #[cfg(windows)]
 use std::ffi::OsString;
@@ -43,7 +64,7 @@ fn run_service() -> windows_service::Result<()> {
     let event_handler = move |control_event| -> ServiceControlHandlerResult {
         match control_event {
             ServiceControl::Stop => {
-                // Handle stop event
+                shutdown::trigger("service stop requested");
                 ServiceControlHandlerResult::NoError
             }
             ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
@@ -69,6 +90,18 @@ fn run_service() -> windows_service::Result<()> {
         start_server().await;
     });
 
+    status_handle.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: ServiceState::StopPending,
+        controls_accepted: ServiceControlAccept::empty(),
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 1,
+        wait_hint: shutdown::SHUTDOWN_TIMEOUT,
+        process_id: None,
+    })?;
+    // Give tasks stuck in blocking USB reads a bounded time to finish
+    rt.shutdown_timeout(shutdown::SHUTDOWN_TIMEOUT);
+
     status_handle.set_service_status(ServiceStatus {
         service_type: ServiceType::OWN_PROCESS,
         current_state: ServiceState::Stopped,
@@ -82,67 +115,34 @@ fn run_service() -> windows_service::Result<()> {
     Ok(())
 }
 
-async fn find_available_port(
-    start_port: u16,
-    max_attempts: u16
-) -> Result<u16, Box<dyn std::error::Error>> {
-    for port in start_port..start_port + max_attempts {
-        let addr = format!("0.0.0.0:{}", port);
-        match tokio::net::TcpListener::bind(&addr).await {
-            Ok(_) => {
-                info!("Found available port: {}", port);
-                return Ok(port);
-            }
-            Err(_) => {
-                info!("Port {} is not available, trying next...", port);
-                continue;
-            }
-        }
-    }
-    Err(
-        format!(
-            "No available port found in range {}..{}",
-            start_port,
-            start_port + max_attempts
-        ).into()
-    )
-}
-
-async fn start_server() {
-    let (socketio_layer, io) = SocketIo::new_layer();
-    io.ns("/", socketio::on_connect);
-    let cors = CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any);
-    let app = axum::Router
-        ::new()
-        .route(
-            "/",
-            get(|| async { "alive" })
-        )
-        .layer(socketio_layer)
-        .layer(cors);
-
-    // Try to find an available port starting from 8436
-    let port = find_available_port(8436, 10).await.unwrap_or_else(|_| {
-        panic!("Could not find an available port");
-    });
-
-    info!("Starting the device on port {}...", port);
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
-}
-
 #[tokio::main]
 async fn main() {
     tracing::subscriber
         ::set_global_default(FmtSubscriber::default())
         .expect("Failed to set global default subscriber");
 
+    let args = cli::parse();
+    let agent_config = config::init(&args);
+
+    if let Some(destination) = &args.export_ca {
+        match tls::export_ca(&agent_config.tls.cert_dir(), destination) {
+            Ok(()) => println!("Exported CA certificate to {}", destination.display()),
+            Err(e) => {
+                eprintln!("Failed to export CA certificate: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if args.self_check {
+        std::process::exit(if selfcheck::run() { 0 } else { 1 });
+    }
+
     #[cfg(windows)]
     {
         // Check if running as a service
-        let args: Vec<String> = std::env::args().collect();
-        if args.len() > 1 && args[1] == "--service" {
+        if args.service {
             // Run as Windows service
             if let Err(e) = service_dispatcher::start("SnappyWebAgent", ffi_service_main) {
                 eprintln!("Failed to start service: {:?}", e);
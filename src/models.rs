@@ -4,16 +4,42 @@ pub const VID: u16 = 0xb1b0;
 // Support multiple PIDs for different device variants
 pub const PIDS: &[u16] = &[0x5508, 0x8055];
 // Keep the original PID for backward compatibility
+#[allow(dead_code)]
 pub const PID: u16 = 0x5508;
 pub const EXPECTED_PREFIX: [u8; 7] = [0x53, 0x4e, 0x41, 0x50, 0x50, 0x59, 0x3a];
 
+// Stable identifiers for failures; `error` strings may change between versions
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    NoDevice,
+    PermissionDenied,
+    PortBusy,
+    PortOpenFailed,
+    // The dongle's serial number, which keys decryption, could not be read
+    KeyUnavailable,
+    DecodeError,
+    Timeout,
+    InvalidRequest,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SerialResponse {
     pub success: bool,
     pub message: String,
     pub command: String,
+    pub code: Option<ErrorCode>,
     pub error: Option<String>,
 }
+
+// `snappy-error`: a device failure no command reply would otherwise report
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnappyErrorEvent {
+    pub code: ErrorCode,
+    pub message: String,
+    pub device: Option<String>,
+    pub timestamp: String,
+}
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct EventResponse {
     pub event: String,
@@ -25,8 +51,500 @@ pub struct SnapDataEvent {
     pub mac: String,
     pub value: u16,
     pub timestamp: String,
-    pub pid: u16
+    pub pid: u16,
+    // Increases by one per live event for the lifetime of the agent; 0 for
+    // debounced events, which are only stored and recorded
+    pub seq: u64,
+    // Identifies the agent run `seq` belongs to; sequence ids restart with each run
+    pub run: u64,
+    // `value` after the [transform] rule for this peripheral; null without one
+    pub calibrated: Option<f64>,
+    pub unit: Option<String>,
+    // Smoothed value when [processing] smooths this peripheral
+    pub filtered: Option<f64>,
+    // Fields added by scripts
+    pub extra: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl SnapDataEvent {
+    // The most processed form of the value: filtered, else calibrated, else raw
+    pub fn processed_value(&self) -> f64 {
+        self.filtered.or(self.calibrated).unwrap_or(self.value as f64)
+    }
+
+    // A raw event as it leaves the decoder, for tests
+    #[cfg(test)]
+    pub fn raw(mac: &str, value: u16, timestamp: &str) -> Self {
+        SnapDataEvent {
+            mac: mac.to_string(),
+            value,
+            timestamp: timestamp.to_string(),
+            pid: PID,
+            seq: 0,
+            run: 0,
+            calibrated: None,
+            unit: None,
+            filtered: None,
+            extra: None,
+        }
+    }
+}
+
+// `set-delivery`: how live events reach this client
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DeliveryOptions {
+    // Send events as `snappy-batch` arrays this often; 0 sends them one by one
+    pub batch_ms: u64,
+    // Events per second at most; 0 is unlimited
+    pub max_rate: u32,
+    // Events waiting beyond this drop the oldest
+    pub max_queue: usize,
+}
+
+impl Default for DeliveryOptions {
+    fn default() -> Self {
+        DeliveryOptions {
+            batch_ms: 0,
+            max_rate: 0,
+            max_queue: 1000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeliveryStats {
+    pub options: DeliveryOptions,
+    pub delivered: u64,
+    pub dropped: u64,
+    pub queued: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeliveryResponse {
+    pub success: bool,
+    pub command: String,
+    pub stats: Option<DeliveryStats>,
+    pub error: Option<String>,
+}
+
+// `hello`: the client's protocol version
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HelloRequest {
+    pub protocol: u64,
+    // Free-form name of the client, for the log
+    #[serde(default)]
+    pub client: Option<String>,
+}
+
+// Optional parts of the agent that are switched on
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Features {
+    pub history: bool,
+    pub tls: bool,
+    pub calibration: bool,
+    pub processing: bool,
+    pub rules: bool,
+    pub scripting: bool,
+    // `device-status` includes the dongle's serial number
+    pub serial: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HelloResponse {
+    pub success: bool,
+    pub command: String,
+    pub agent_version: String,
+    // Protocol used for this client from now on
+    pub protocol: Option<u64>,
+    pub supported_protocols: Vec<u64>,
+    pub commands: Vec<String>,
+    pub events: Vec<String>,
+    pub encodings: Vec<Encoding>,
+    pub features: Features,
+    pub error: Option<String>,
+}
+
+// `device-status`: the dongle and what the agent is doing with it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DeviceStatus {
+    pub connected: bool,
+    pub vid: u16,
+    pub pid: Option<u16>,
+    pub port: Option<String>,
+    // Only with [device] expose_serial = true
+    pub serial: Option<String>,
+    pub profile: Option<String>,
+    pub collecting: bool,
+    // Id of the session being recorded, if any
+    pub recording: Option<String>,
+}
+
+// /v2 `version`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VersionResponse {
+    pub success: bool,
+    pub command: String,
+    pub version: String,
+    pub error: Option<String>,
+}
+
+// /v2 `device-info`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeviceInfoResponse {
+    pub success: bool,
+    pub command: String,
+    pub vid: u16,
+    pub supported_pids: Vec<u16>,
+    pub device: DeviceStatus,
+    pub error: Option<String>,
 }
+
+// /v2 `start-snappy` and `stop-snappy`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CollectionResponse {
+    pub success: bool,
+    pub command: String,
+    pub subscribed: bool,
+    // This client's subscription; null for every peripheral
+    pub macs: Option<Vec<String>>,
+    pub collecting: bool,
+    pub subscribers: usize,
+    pub code: Option<ErrorCode>,
+    pub error: Option<String>,
+}
+
+// /v2 `agent-shutdown`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShutdownEvent {
+    pub reason: String,
+}
+
+// How snap data is sent to a client
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    // Packed little-endian records as a Socket.IO binary attachment
+    Binary,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EncodingResponse {
+    pub success: bool,
+    pub command: String,
+    pub encoding: Option<Encoding>,
+    // Bytes per event in the binary format
+    pub record_size: usize,
+    pub error: Option<String>,
+}
+
+// `snappy-batch`: queued events for a client that asked for batching
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnapBatchEvent {
+    pub events: Vec<SnapDataEvent>,
+    // Events dropped for this client so far
+    pub dropped: u64,
+}
+
+// `snappy-dropped`: events this client missed since the last notice
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DroppedEvent {
+    pub dropped: u64,
+    pub total: u64,
+}
+
+// `subscribe-aggregate`: exactly one of window_ms and resample_hz
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct AggregateRequest {
+    pub window_ms: Option<u64>,
+    pub resample_hz: Option<f64>,
+    // Every peripheral when absent
+    pub macs: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AggregateResponse {
+    pub success: bool,
+    pub command: String,
+    pub subscription: Option<AggregateRequest>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AggregateStats {
+    pub mac: String,
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+// `snappy-aggregate`: one window of a client's aggregated stream
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AggregateEvent {
+    pub window_start: String,
+    pub window_end: String,
+    pub stats: Vec<AggregateStats>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ResampledValue {
+    pub mac: String,
+    pub value: f64,
+    // When the held value was received
+    pub timestamp: String,
+}
+
+// `snappy-resampled`: the latest value of every peripheral, at a fixed rate
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ResampledEvent {
+    pub timestamp: String,
+    pub values: Vec<ResampledValue>,
+}
+
+// `snappy-change`: the (smoothed) value moved by at least the change threshold
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnapChangeEvent {
+    pub mac: String,
+    pub pid: u16,
+    pub device: String,
+    pub previous: f64,
+    pub value: f64,
+    pub timestamp: String,
+}
+
+// `snappy-trigger`: a [[rules]] condition held long enough
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TriggerEvent {
+    pub rule: String,
+    pub mac: String,
+    pub pid: u16,
+    pub device: String,
+    pub value: f64,
+    pub timestamp: String,
+}
+
+// `snappy-peak`: the value rose above the peak threshold and fell back
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnapPeakEvent {
+    pub mac: String,
+    pub pid: u16,
+    pub device: String,
+    pub peak: f64,
+    pub peak_at: String,
+    pub started_at: String,
+    pub ended_at: String,
+}
+
+// The most recent event from one peripheral through one dongle
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LatestValue {
+    pub device: String,
+    #[serde(flatten)]
+    pub event: SnapDataEvent,
+}
+
+// Ack for `snapshot`, also sent as `snappy-snapshot` when a client subscribes
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnapshotResponse {
+    pub success: bool,
+    pub command: String,
+    pub values: Vec<LatestValue>,
+    pub error: Option<String>,
+}
+
+// Ack for `resume`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ResumeResponse {
+    pub success: bool,
+    pub command: String,
+    // Number of missed events sent before this ack
+    pub replayed: usize,
+    // Sequence id of the newest event emitted so far
+    pub last_seq: u64,
+    // The current agent run, to send back with the next resume
+    pub run: u64,
+    // Some missed events had already left the replay buffer
+    pub gap: bool,
+    pub error: Option<String>,
+}
+// A snap data event as kept by the history store
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoredSnapData {
+    pub id: i64,
+    // Serial port (or USB device) the event was read from
+    pub device: String,
+    pub mac: String,
+    pub value: u16,
+    pub pid: u16,
+    pub timestamp: String,
+    // Same instant as `timestamp`, in milliseconds since the Unix epoch
+    pub timestamp_ms: i64,
+}
+
+// Filters for the `history` command and GET /history; times are RFC 3339
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct HistoryQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub device: Option<String>,
+    pub mac: Option<String>,
+    pub pid: Option<u16>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryResponse {
+    pub success: bool,
+    pub command: String,
+    pub records: Vec<StoredSnapData>,
+    // More records matched than `limit` allowed
+    pub truncated: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStatus {
+    Recording,
+    Stopped,
+    // The agent stopped before the session did
+    Interrupted,
+}
+
+// A named recording session and its metadata
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionInfo {
+    pub id: String,
+    pub name: String,
+    pub operator: Option<String>,
+    pub notes: Option<String>,
+    pub status: SessionStatus,
+    pub started_at: String,
+    pub stopped_at: Option<String>,
+    pub event_count: u64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct StartRecording {
+    pub name: String,
+    #[serde(default)]
+    pub operator: Option<String>,
+    #[serde(default)]
+    pub notes: Option<String>,
+}
+
+// One captured event, as written to the session file and exports
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionEvent {
+    pub seq: u64,
+    pub timestamp: String,
+    pub device: String,
+    pub mac: String,
+    pub value: u16,
+    pub pid: u16,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionResponse {
+    pub success: bool,
+    pub command: String,
+    pub session: Option<SessionInfo>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionListResponse {
+    pub success: bool,
+    pub command: String,
+    pub sessions: Vec<SessionInfo>,
+    pub error: Option<String>,
+}
+
+// A peripheral (MAC) the dongle has received frames from
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Peripheral {
+    pub mac: String,
+    pub alias: Option<String>,
+    pub first_seen: String,
+    pub last_seen: String,
+    pub frame_count: u64,
+    pub last_value: u16,
+    // Dongle the last frame came through
+    pub pid: u16,
+    pub device: String,
+    // Reported within the lost timeout
+    pub online: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PeripheralsResponse {
+    pub success: bool,
+    pub command: String,
+    pub peripherals: Vec<Peripheral>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct PeripheralAlias {
+    #[serde(default)]
+    pub mac: String,
+    pub alias: Option<String>,
+}
+
+// Agent-wide MAC allow/deny lists; an empty allowlist allows every MAC
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct MacFilterLists {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+// Lists left out are kept as they are
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct MacFilterUpdate {
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MacFilterResponse {
+    pub success: bool,
+    pub command: String,
+    pub filter: Option<MacFilterLists>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SubscriptionResponse {
+    pub success: bool,
+    pub command: String,
+    // None when the client receives every peripheral
+    pub macs: Option<Vec<String>>,
+    pub error: Option<String>,
+}
+
+// Published in the discovery file and served at /discovery so clients can
+// find the agent without probing every port
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DiscoveryInfo {
+    pub pid: u32,
+    pub version: String,
+    pub port: u16,
+    pub tls_port: Option<u16>,
+    // Null when the discovery port is disabled or taken
+    #[serde(default)]
+    pub discovery_port: Option<u16>,
+    pub addresses: Vec<String>,
+    pub unix_socket: Option<String>,
+    pub started_at: String,
+}
+
 #[derive(Deserialize)]
 pub struct CargoToml {
     pub package: Package,
@@ -0,0 +1,139 @@
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+const APP_DIR_NAME: &str = "snappy-web-agent";
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+const APP_DIR_NAME_CAMEL: &str = "SnappyWebAgent";
+
+// Directory holding the optional config.toml
+pub fn config_dir() -> PathBuf {
+    #[cfg(target_os = "windows")]
+    {
+        program_data_dir()
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        PathBuf::from("/Library/Application Support").join(APP_DIR_NAME_CAMEL)
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    {
+        PathBuf::from("/etc").join(APP_DIR_NAME)
+    }
+}
+
+// Directory for state the agent creates itself (certificates, databases, ...).
+// Resolved once, so it does not move when the agent drops root.
+pub fn data_dir() -> PathBuf {
+    static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
+    DATA_DIR.get_or_init(resolve_data_dir).clone()
+}
+
+fn resolve_data_dir() -> PathBuf {
+    #[cfg(target_os = "windows")]
+    {
+        program_data_dir()
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        if is_root() {
+            return config_dir();
+        }
+        match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join("Library/Application Support").join(APP_DIR_NAME_CAMEL),
+            None => PathBuf::from("/Library/Application Support").join(APP_DIR_NAME_CAMEL),
+        }
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    {
+        // systemd sets STATE_DIRECTORY when the unit declares StateDirectory=
+        if let Some(dir) = std::env::var_os("STATE_DIRECTORY") {
+            return PathBuf::from(dir);
+        }
+        if is_root() {
+            return PathBuf::from("/var/lib").join(APP_DIR_NAME);
+        }
+        if let Some(dir) = std::env::var_os("XDG_DATA_HOME") {
+            return PathBuf::from(dir).join(APP_DIR_NAME);
+        }
+        match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".local/share").join(APP_DIR_NAME),
+            None => PathBuf::from("/var/lib").join(APP_DIR_NAME),
+        }
+    }
+}
+
+// Directory for files that only live as long as the agent runs (discovery
+// file, locks). Clients look here to find a running agent. Resolved once like data_dir.
+pub fn runtime_dir() -> PathBuf {
+    static RUNTIME_DIR: OnceLock<PathBuf> = OnceLock::new();
+    RUNTIME_DIR.get_or_init(resolve_runtime_dir).clone()
+}
+
+fn resolve_runtime_dir() -> PathBuf {
+    #[cfg(target_os = "windows")]
+    {
+        program_data_dir()
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        if is_root() {
+            return PathBuf::from("/var/run").join(APP_DIR_NAME);
+        }
+        std::env::temp_dir().join(APP_DIR_NAME)
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    {
+        // systemd sets RUNTIME_DIRECTORY when the unit declares RuntimeDirectory=
+        if let Some(dir) = std::env::var_os("RUNTIME_DIRECTORY") {
+            return PathBuf::from(dir);
+        }
+        if is_root() {
+            return PathBuf::from("/run").join(APP_DIR_NAME);
+        }
+        match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) => PathBuf::from(dir).join(APP_DIR_NAME),
+            None => {
+                // SAFETY: getuid has no preconditions and cannot fail
+                let uid = unsafe { libc::getuid() };
+                std::env::temp_dir().join(format!("{}-{}", APP_DIR_NAME, uid))
+            }
+        }
+    }
+}
+
+// Directory for the single-instance lock: every user can open files in it, but
+// only root can create them, so no user can plant a lock of their own
+pub fn shared_lock_dir() -> PathBuf {
+    #[cfg(target_os = "windows")]
+    {
+        program_data_dir()
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        PathBuf::from("/var/run")
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    {
+        PathBuf::from("/run")
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn program_data_dir() -> PathBuf {
+    let base = std::env::var_os("ProgramData").unwrap_or_else(|| "C:\\ProgramData".into());
+    PathBuf::from(base).join(APP_DIR_NAME_CAMEL)
+}
+
+#[cfg(unix)]
+pub fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    (unsafe { libc::geteuid() }) == 0
+}
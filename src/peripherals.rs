@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{ Mutex, OnceLock };
+use std::time::Duration;
+use chrono::{ DateTime, Utc };
+use tracing::info;
+use crate::{ config, models::*, paths, shutdown, socketio };
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct Registry {
+    peripherals: HashMap<String, Peripheral>,
+    // Changed since the last save
+    dirty: bool,
+}
+
+static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+
+fn registry() -> &'static Mutex<Registry> {
+    REGISTRY.get_or_init(|| Mutex::new(load()))
+}
+
+fn registry_path() -> PathBuf {
+    paths::data_dir().join("peripherals.json")
+}
+
+// Peripherals from earlier runs start out offline
+fn load() -> Registry {
+    let peripherals: Vec<Peripheral> = std::fs
+        ::read(registry_path())
+        .ok()
+        .and_then(|content| serde_json::from_slice(&content).ok())
+        .unwrap_or_default();
+    Registry {
+        peripherals: peripherals
+            .into_iter()
+            .map(|mut peripheral| {
+                peripheral.online = false;
+                (peripheral.mac.clone(), peripheral)
+            })
+            .collect(),
+        dirty: false,
+    }
+}
+
+pub fn save() {
+    let Ok(mut registry) = registry().lock() else {
+        return;
+    };
+    if !registry.dirty {
+        return;
+    }
+    let path = registry_path();
+    let result = (|| -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(&sorted(&registry.peripherals))?)?;
+        std::fs::rename(&tmp_path, &path)
+    })();
+    match result {
+        Ok(()) => {
+            registry.dirty = false;
+        }
+        Err(e) => info!("Failed to save peripheral registry {}: {}", path.display(), e),
+    }
+}
+
+fn sorted(peripherals: &HashMap<String, Peripheral>) -> Vec<Peripheral> {
+    let mut list: Vec<Peripheral> = peripherals.values().cloned().collect();
+    list.sort_by(|a, b| a.mac.cmp(&b.mac));
+    list
+}
+
+pub fn normalize_mac(mac: &str) -> String {
+    mac.trim().to_lowercase().replace('-', ":")
+}
+
+// Record a frame from `mac`, which the MAC filter has already let through;
+// announces peripherals that were not reporting
+pub fn observe(mac: &str, value: u16, pid: u16, device: &str) {
+    let Ok(mut registry) = registry().lock() else {
+        return;
+    };
+    let now = Utc::now().to_rfc3339();
+    registry.dirty = true;
+    let peripheral = registry.peripherals.entry(mac.to_string()).or_insert_with(|| Peripheral {
+        mac: mac.to_string(),
+        alias: None,
+        first_seen: now.clone(),
+        last_seen: now.clone(),
+        frame_count: 0,
+        last_value: value,
+        pid,
+        device: device.to_string(),
+        online: false,
+    });
+    peripheral.last_seen = now;
+    peripheral.frame_count += 1;
+    peripheral.last_value = value;
+    peripheral.pid = pid;
+    peripheral.device = device.to_string();
+
+    if !peripheral.online {
+        peripheral.online = true;
+        info!("Peripheral {} appeared on {}", peripheral.mac, device);
+        socketio::broadcast("peripheral-appeared", peripheral.clone());
+    }
+}
+
+pub fn list() -> Vec<Peripheral> {
+    registry()
+        .lock()
+        .map(|registry| sorted(&registry.peripherals))
+        .unwrap_or_default()
+}
+
+pub fn get(mac: &str) -> Option<Peripheral> {
+    registry().lock().ok()?.peripherals.get(&normalize_mac(mac)).cloned()
+}
+
+// Name a peripheral; an empty or missing alias clears it
+pub fn set_alias(mac: &str, alias: Option<String>) -> Result<Peripheral, String> {
+    let peripheral = {
+        let mut registry = registry().lock().map_err(|_| "peripheral registry is unavailable")?;
+        let mac = normalize_mac(mac);
+        let peripheral = registry.peripherals.get_mut(&mac).ok_or_else(|| format!("unknown peripheral {}", mac))?;
+        peripheral.alias = alias.map(|alias| alias.trim().to_string()).filter(|alias| !alias.is_empty());
+        let peripheral = peripheral.clone();
+        registry.dirty = true;
+        peripheral
+    };
+    save();
+    Ok(peripheral)
+}
+
+pub fn response(result: Result<Vec<Peripheral>, String>) -> PeripheralsResponse {
+    match result {
+        Ok(peripherals) =>
+            PeripheralsResponse {
+                success: true,
+                command: "peripherals".to_string(),
+                peripherals,
+                error: None,
+            },
+        Err(e) =>
+            PeripheralsResponse {
+                success: false,
+                command: "peripherals".to_string(),
+                peripherals: Vec::new(),
+                error: Some(e),
+            },
+    }
+}
+
+// Mark peripherals that stopped reporting as lost and persist the registry now and then
+pub fn spawn_monitor() {
+    let timeout = chrono::Duration::seconds(config::get().peripherals.lost_timeout_secs as i64);
+    let save_every = config::get().peripherals.save_interval_secs.max(1);
+    tokio::spawn(async move {
+        let mut ticks = 0u64;
+        while !shutdown::is_shutting_down() {
+            shutdown::sleep(CHECK_INTERVAL).await;
+
+            let lost: Vec<Peripheral> = match registry().lock() {
+                Ok(mut registry) => {
+                    let now = Utc::now();
+                    let mut lost = Vec::new();
+                    for peripheral in registry.peripherals.values_mut() {
+                        let silent = DateTime::parse_from_rfc3339(&peripheral.last_seen)
+                            .map(|last_seen| now.signed_duration_since(last_seen) > timeout)
+                            .unwrap_or(true);
+                        if peripheral.online && silent {
+                            peripheral.online = false;
+                            lost.push(peripheral.clone());
+                        }
+                    }
+                    lost
+                }
+                Err(_) => Vec::new(),
+            };
+            for peripheral in lost {
+                info!("Peripheral {} lost", peripheral.mac);
+                socketio::broadcast("peripheral-lost", peripheral);
+            }
+
+            ticks += 1;
+            if ticks.is_multiple_of(save_every) {
+                tokio::task::spawn_blocking(save);
+            }
+        }
+    });
+}
@@ -0,0 +1,213 @@
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use tracing::info;
+#[cfg(unix)]
+use crate::paths;
+
+#[cfg(target_os = "linux")]
+pub const UDEV_RULES_PATH: &str = "/etc/udev/rules.d/99-snappy-web-agent.rules";
+
+#[cfg(unix)]
+pub struct Account {
+    pub name: String,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+#[cfg(unix)]
+fn lookup(name: Option<&str>, uid: libc::uid_t) -> Option<Account> {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    // SAFETY: every pointer refers to a live buffer of the stated size
+    let status = match name {
+        Some(name) => {
+            let name = std::ffi::CString::new(name).ok()?;
+            unsafe {
+                libc::getpwnam_r(name.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result)
+            }
+        }
+        None => unsafe { libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) },
+    };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+    // SAFETY: on success pw_name points to a NUL-terminated string inside `buffer`
+    let name = unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) }.to_string_lossy().into_owned();
+    Some(Account { name, uid: passwd.pw_uid, gid: passwd.pw_gid })
+}
+
+#[cfg(unix)]
+pub fn lookup_user(name: &str) -> Option<Account> {
+    lookup(Some(name), 0)
+}
+
+// Name of the effective user, for log messages
+#[cfg(unix)]
+pub fn current_user() -> String {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    let uid = unsafe { libc::geteuid() };
+    lookup(None, uid).map(|account| account.name).unwrap_or_else(|| format!("uid {}", uid))
+}
+
+#[cfg(unix)]
+pub fn group_name(gid: libc::gid_t) -> String {
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut result: *mut libc::group = std::ptr::null_mut();
+    // SAFETY: every pointer refers to a live buffer of the stated size
+    let status = unsafe { libc::getgrgid_r(gid, &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if status != 0 || result.is_null() {
+        return gid.to_string();
+    }
+    // SAFETY: on success gr_name points to a NUL-terminated string inside `buffer`
+    unsafe { std::ffi::CStr::from_ptr(group.gr_name) }.to_string_lossy().into_owned()
+}
+
+#[cfg(unix)]
+pub fn lookup_group(name: &str) -> Option<libc::gid_t> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut result: *mut libc::group = std::ptr::null_mut();
+    // SAFETY: every pointer refers to a live buffer of the stated size
+    let status = unsafe { libc::getgrnam_r(name.as_ptr(), &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+    Some(group.gr_gid)
+}
+
+// Effective and supplementary groups of this process
+#[cfg(unix)]
+pub fn process_groups() -> Vec<libc::gid_t> {
+    // SAFETY: a zero-sized query only returns the count
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    let mut groups = vec![0 as libc::gid_t; count.max(0) as usize];
+    // SAFETY: `groups` has room for `count` entries
+    let filled = unsafe { libc::getgroups(groups.len() as libc::c_int, groups.as_mut_ptr()) };
+    groups.truncate(filled.max(0) as usize);
+    // SAFETY: getegid has no preconditions and cannot fail
+    groups.push(unsafe { libc::getegid() });
+    groups
+}
+
+// True for EACCES, and for EPERM which the device cgroup (DeviceAllow=) returns
+#[cfg(not(target_os = "windows"))]
+pub fn is_permission_denied(error: &serialport::Error) -> bool {
+    if let serialport::ErrorKind::Io(kind) = error.kind() {
+        return kind == std::io::ErrorKind::PermissionDenied;
+    }
+    error.description.contains("not permitted")
+}
+
+// Explain why `path` could not be opened and what would fix it
+#[cfg(unix)]
+pub fn device_access_hint(path: &str) -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    let user = current_user();
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            return format!("Cannot access {} as user {}: {}", path, user, e);
+        }
+    };
+    let mode = metadata.mode() & 0o777;
+    let group = group_name(metadata.gid());
+    let mut hint = format!(
+        "Permission denied opening {} as user {} (device group {}, mode {:o}).",
+        path,
+        user,
+        group,
+        mode
+    );
+
+    if mode & 0o006 == 0o006 || (process_groups().contains(&metadata.gid()) && mode & 0o060 == 0o060) {
+        // The file permissions allow us in, so something outside them is refusing
+        hint.push_str(
+            " The file permissions allow access, so the service sandbox is blocking it: check DevicePolicy=/DeviceAllow= in the unit."
+        );
+    } else if group == "root" {
+        hint.push_str(" The device has no access group; the udev rule has not been applied.");
+    } else {
+        hint.push_str(
+            &format!(
+                " Add the user to the '{}' group (sudo usermod -aG {} {}) and restart the agent or log in again.",
+                group,
+                group,
+                user
+            )
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    if !Path::new(UDEV_RULES_PATH).exists() {
+        hint.push_str(
+            &format!(" The udev rule {} is not installed; install it and replug the device.", UDEV_RULES_PATH)
+        );
+    }
+    hint
+}
+
+#[cfg(not(unix))]
+pub fn device_access_hint(path: &str) -> String {
+    format!("Permission denied opening {}", path)
+}
+
+#[cfg(unix)]
+fn chown_tree(path: &Path, account: &Account) -> std::io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    std::os::unix::fs::lchown(path, Some(account.uid), Some(account.gid))?;
+    if path.is_dir() && !path.is_symlink() {
+        for entry in std::fs::read_dir(path)? {
+            chown_tree(&entry?.path(), account)?;
+        }
+    }
+    Ok(())
+}
+
+// Switch from root to `user` once everything that needs root is done. The
+// agent's own directories are handed over so it can keep writing to them.
+#[cfg(unix)]
+pub fn drop_privileges(user: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if !paths::is_root() {
+        info!("Not running as root, staying user {}", current_user());
+        return Ok(());
+    }
+    let account = lookup_user(user).ok_or_else(|| format!("unknown user {}", user))?;
+
+    for dir in [paths::data_dir(), paths::runtime_dir()] {
+        chown_tree(&dir, &account)?;
+    }
+
+    let name = std::ffi::CString::new(account.name.clone())?;
+    // SAFETY: plain syscalls; glibc and musl apply set*id to every thread
+    unsafe {
+        if libc::initgroups(name.as_ptr(), account.gid as _) != 0 {
+            return Err(format!("initgroups failed: {}", std::io::Error::last_os_error()).into());
+        }
+        if libc::setgid(account.gid) != 0 {
+            return Err(format!("setgid failed: {}", std::io::Error::last_os_error()).into());
+        }
+        if libc::setuid(account.uid) != 0 {
+            return Err(format!("setuid failed: {}", std::io::Error::last_os_error()).into());
+        }
+        // Getting root back must be impossible now
+        if libc::setuid(0) == 0 {
+            return Err("privileges could be regained after setuid".into());
+        }
+    }
+
+    let groups: Vec<String> = process_groups().into_iter().map(group_name).collect();
+    info!("Dropped privileges to user {} (groups {})", account.name, groups.join(", "));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(_user: &str) -> Result<(), Box<dyn std::error::Error>> {
+    Err("dropping privileges is not supported on this platform".into())
+}
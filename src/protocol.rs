@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::sync::{ Mutex, MutexGuard, OnceLock };
+use serde_json::Value;
+use socketioxide::socket::Sid;
+use crate::{ config, models::*, rules, store };
+
+// Oldest first. Protocol 2 drops the legacy `device-connected` event.
+pub const SUPPORTED: &[u64] = &[1, 2];
+
+// Namespace with typed responses; its clients speak protocol 2 or later
+pub const V2_NAMESPACE: &str = "/v2";
+
+// Protocols a namespace speaks, oldest first
+pub fn supported(ns: &str) -> &'static [u64] {
+    if ns == V2_NAMESPACE { &SUPPORTED[1..] } else { SUPPORTED }
+}
+
+// Keep in step with the handlers registered in socketio::on_connect
+pub const COMMANDS: &[&str] = &[
+    "hello",
+    "version",
+    "device-info",
+    "start-snappy",
+    "stop-snappy",
+    "resume",
+    "snapshot",
+    "set-subscription",
+    "subscribe-aggregate",
+    "unsubscribe-aggregate",
+    "set-delivery",
+    "delivery-stats",
+    "set-encoding",
+    "get-mac-filter",
+    "set-mac-filter",
+    "history",
+    "start-recording",
+    "stop-recording",
+    "list-recordings",
+    "peripherals",
+    "set-peripheral-alias",
+];
+
+// Events the agent emits; scripts add their own as `script:<name>`
+pub const EVENTS: &[&str] = &[
+    "device-status",
+    "device-connected",
+    "snappy-data",
+    "snappy-batch",
+    "snappy-dropped",
+    "snappy-snapshot",
+    "snappy-aggregate",
+    "snappy-resampled",
+    "snappy-change",
+    "snappy-peak",
+    "snappy-trigger",
+    "snappy-error",
+    "peripheral-appeared",
+    "peripheral-lost",
+    "agent-shutdown",
+];
+
+// Negotiated protocol per client, for those that declared one
+static PROTOCOLS: OnceLock<Mutex<HashMap<Sid, u64>>> = OnceLock::new();
+
+fn protocols() -> MutexGuard<'static, HashMap<Sid, u64>> {
+    PROTOCOLS.get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+// Protocol the client uses; 1 until it declares one
+pub fn of(sid: Sid) -> u64 {
+    protocols().get(&sid).copied().unwrap_or(1)
+}
+
+pub fn remove(sid: Sid) {
+    protocols().remove(&sid);
+}
+
+// The highest protocol both sides speak
+fn negotiate(sid: Sid, ns: &str, requested: u64) -> Result<u64, String> {
+    let supported = supported(ns);
+    let protocol = requested.min(supported[supported.len() - 1]);
+    if !supported.contains(&protocol) {
+        return Err(format!("protocol {} is not supported on {} (supported: {:?})", requested, ns, supported));
+    }
+    protocols().insert(sid, protocol);
+    Ok(protocol)
+}
+
+// Clients start on their namespace's oldest protocol; a version in the
+// connection auth data counts as an early hello
+pub fn from_auth(sid: Sid, ns: &str, data: &Value) {
+    protocols().insert(sid, supported(ns)[0]);
+    if let Some(requested) = data.get("protocol").and_then(Value::as_u64) {
+        let _ = negotiate(sid, ns, requested);
+    }
+}
+
+// Events a client on `protocol` can receive
+fn events(protocol: u64) -> Vec<String> {
+    EVENTS.iter()
+        .filter(|&&event| protocol < 2 || event != "device-connected")
+        .map(|event| event.to_string())
+        .collect()
+}
+
+fn features() -> Features {
+    let config = config::get();
+    Features {
+        history: store::is_enabled(),
+        tls: config.tls.enabled,
+        calibration: config.transform.default.is_some() || !config.transform.pid.is_empty() || !config.transform.mac.is_empty(),
+        processing: config.processing.default.is_some() || !config.processing.mac.is_empty(),
+        rules: rules::any(),
+        scripting: config.scripting.enabled,
+        serial: config.device.expose_serial,
+    }
+}
+
+pub fn hello(sid: Sid, ns: &str, request: Result<HelloRequest, String>) -> HelloResponse {
+    let (protocol, error) = match request.and_then(|request| negotiate(sid, ns, request.protocol)) {
+        Ok(protocol) => (Some(protocol), None),
+        Err(e) => (None, Some(e)),
+    };
+    HelloResponse {
+        success: error.is_none(),
+        command: "hello".to_string(),
+        agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        protocol,
+        supported_protocols: supported(ns).to_vec(),
+        commands: COMMANDS.iter().map(|command| command.to_string()).collect(),
+        events: events(of(sid)),
+        encodings: vec![Encoding::Json, Encoding::Binary],
+        features: features(),
+        error,
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::sync::{ Mutex, OnceLock };
+use std::time::{ Duration, Instant };
+use tracing::info;
+use crate::{ config::{ self, TriggerRule }, filter, models::*, serial, shutdown, socketio };
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+// How often held conditions are checked between frames
+const HOLD_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+// Where one rule stands for one peripheral
+struct Condition {
+    // When the condition started holding
+    since: Instant,
+    fired: bool,
+    // The latest frame that kept it holding, reported if the timer fires the rule
+    last: TriggerEvent,
+}
+
+struct Rule {
+    config: TriggerRule,
+    mac: Option<String>,
+    hold: Duration,
+}
+
+static RULES: OnceLock<Vec<Rule>> = OnceLock::new();
+// Keyed by rule index and MAC; absent while the condition does not hold
+static CONDITIONS: OnceLock<Mutex<HashMap<(usize, String), Condition>>> = OnceLock::new();
+
+fn rules() -> &'static [Rule] {
+    RULES.get_or_init(|| {
+        config
+            ::get()
+            .rules.iter()
+            .filter_map(|rule| {
+                if rule.above.is_none() && rule.below.is_none() {
+                    info!("Ignoring rule '{}': it needs `above` and/or `below`", rule.name);
+                    return None;
+                }
+                let mac = match rule.mac.as_deref().map(filter::parse_mac) {
+                    Some(None) => {
+                        info!("Ignoring rule '{}': invalid MAC {:?}", rule.name, rule.mac);
+                        return None;
+                    }
+                    Some(mac) => mac,
+                    None => None,
+                };
+                // USB connections on Windows cannot write to the dongle
+                if cfg!(target_os = "windows") && rule.command.is_some() {
+                    info!("Ignoring rule '{}': device commands are not supported on Windows", rule.name);
+                    return None;
+                }
+                Some(Rule { config: rule.clone(), mac, hold: Duration::from_millis(rule.for_ms) })
+            })
+            .collect()
+    })
+}
+
+// Rules need the stream even when no client is subscribed
+pub fn any() -> bool {
+    !rules().is_empty()
+}
+
+impl Rule {
+    fn holds(&self, value: f64) -> bool {
+        self.config.above.is_none_or(|above| value > above) && self.config.below.is_none_or(|below| value < below)
+    }
+}
+
+// Check an event against every rule. A rule fires once when its condition has
+// held for `for_ms`, and again only after the condition stopped holding.
+pub fn evaluate(event: &SnapDataEvent, device: &str) {
+    let rules = rules();
+    if rules.is_empty() {
+        return;
+    }
+    let value = event.processed_value();
+    let mut conditions = CONDITIONS.get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+
+    for (index, rule) in rules.iter().enumerate() {
+        if rule.mac.as_ref().is_some_and(|mac| *mac != event.mac) {
+            continue;
+        }
+        let key = (index, event.mac.clone());
+        if !rule.holds(value) {
+            conditions.remove(&key);
+            continue;
+        }
+        let last = TriggerEvent {
+            rule: rule.config.name.clone(),
+            mac: event.mac.clone(),
+            pid: event.pid,
+            device: device.to_string(),
+            value,
+            timestamp: event.timestamp.clone(),
+        };
+        let condition = conditions.entry(key).or_insert_with(|| Condition {
+            since: Instant::now(),
+            fired: false,
+            last: last.clone(),
+        });
+        condition.last = last;
+        if condition.fired || condition.since.elapsed() < rule.hold {
+            continue;
+        }
+        condition.fired = true;
+        fire(rule, condition.last.clone());
+    }
+}
+
+// Fire rules whose condition has been holding for `for_ms` since the last frame,
+// so a peripheral that goes quiet while above a threshold still triggers
+pub fn spawn_hold_timer() {
+    if !rules().iter().any(|rule| !rule.hold.is_zero()) {
+        return;
+    }
+    tokio::spawn(async {
+        while !shutdown::is_shutting_down() {
+            shutdown::sleep(HOLD_CHECK_INTERVAL).await;
+            let rules = rules();
+            let mut conditions = CONDITIONS.get_or_init(Default::default)
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            for ((index, _), condition) in conditions.iter_mut() {
+                let rule = &rules[*index];
+                if condition.fired || condition.since.elapsed() < rule.hold {
+                    continue;
+                }
+                condition.fired = true;
+                let mut trigger = condition.last.clone();
+                trigger.timestamp = chrono::Utc::now().to_rfc3339();
+                fire(rule, trigger);
+            }
+        }
+    });
+}
+
+fn fire(rule: &Rule, trigger: TriggerEvent) {
+    info!("Rule '{}' triggered by {} (value {})", trigger.rule, trigger.mac, trigger.value);
+    if let Some(command) = &rule.config.command {
+        serial::queue_command(command);
+    }
+    if let Some(url) = &rule.config.webhook {
+        post_webhook(url.clone(), trigger.clone());
+    }
+    socketio::broadcast("snappy-trigger", trigger);
+}
+
+// POST the trigger as JSON, off the device loop
+fn post_webhook(url: String, trigger: TriggerEvent) {
+    tokio::task::spawn_blocking(move || {
+        let agent = ureq::Agent::config_builder().timeout_global(Some(WEBHOOK_TIMEOUT)).build().new_agent();
+        if let Err(e) = agent.post(&url).send_json(&trigger) {
+            info!("Webhook {} for rule '{}' failed: {}", url, trigger.rule, e);
+        }
+    });
+}
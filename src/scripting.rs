@@ -0,0 +1,222 @@
+use std::cell::RefCell;
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::{ OnceLock, RwLock };
+use std::time::{ Duration, SystemTime };
+use rhai::{ CallFnOptions, Dynamic, Engine, Scope, AST };
+use serde::Serialize;
+use serde_json::{ Map, Value };
+use tracing::info;
+use crate::{ config::{ self, ScriptingConfig }, shutdown, socketio };
+
+// Script events are namespaced so they can never pass for the agent's own
+const EVENT_PREFIX: &str = "script:";
+
+// A decoded frame on its way to `emit_snap_data`, as scripts see it
+#[derive(Serialize, Clone, Debug)]
+pub struct Frame {
+    pub mac: String,
+    pub value: u16,
+    pub pid: u16,
+    pub device: String,
+    // Fields added by scripts
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+struct Script {
+    name: String,
+    ast: AST,
+    // Runtime errors are logged once per load, not once per frame
+    failed: AtomicBool,
+}
+
+static ENGINE: OnceLock<Engine> = OnceLock::new();
+static SCRIPTS: RwLock<Vec<Script>> = RwLock::new(Vec::new());
+
+thread_local! {
+    // Events a script asked for with emit(), sent once it returns
+    static EMITTED: RefCell<Vec<(String, Value)>> = const { RefCell::new(Vec::new()) };
+}
+
+// No file, network or process access; the limits bound what a script can cost per frame
+fn engine() -> &'static Engine {
+    ENGINE.get_or_init(|| {
+        let limits = &config::get().scripting;
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(limits.max_operations)
+            .set_max_call_levels(limits.max_call_levels)
+            .set_max_string_size(limits.max_string_size)
+            .set_max_array_size(limits.max_array_size)
+            .set_max_map_size(limits.max_map_size);
+        engine.on_print(|text| info!("script: {}", text));
+        engine.on_debug(|text, source, position| info!("script {}{}: {}", source.unwrap_or(""), position, text));
+        engine.register_fn("emit", |name: &str, data: Dynamic| {
+            let data = rhai::serde::from_dynamic::<Value>(&data).unwrap_or(Value::Null);
+            EMITTED.with(|emitted| emitted.borrow_mut().push((name.to_string(), data)));
+        });
+        engine
+    })
+}
+
+// *.rhai files in the scripts directory, in name order
+fn script_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = std::fs
+        ::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|extension| extension == "rhai"))
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+fn load(dir: &Path) {
+    let mut scripts = Vec::new();
+    for path in script_files(dir) {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        match engine().compile_file(path.clone()) {
+            Ok(ast) if ast.iter_functions().any(|f| f.name == "on_frame" && f.params.len() == 1) => {
+                scripts.push(Script { name, ast, failed: AtomicBool::new(false) });
+            }
+            Ok(_) => info!("Skipping script {}: it does not define on_frame(frame)", name),
+            Err(e) => info!("Skipping script {}: {}", name, e),
+        }
+    }
+    info!(
+        "Loaded {} script(s) from {}: {:?}",
+        scripts.len(),
+        dir.display(),
+        scripts
+            .iter()
+            .map(|script| &script.name)
+            .collect::<Vec<_>>()
+    );
+    *SCRIPTS.write().unwrap_or_else(|e| e.into_inner()) = scripts;
+}
+
+// Changes to the set of scripts or their contents
+fn fingerprint(dir: &Path) -> Vec<(PathBuf, Option<SystemTime>, u64)> {
+    script_files(dir)
+        .into_iter()
+        .map(|path| {
+            let metadata = std::fs::metadata(&path).ok();
+            let modified = metadata.as_ref().and_then(|metadata| metadata.modified().ok());
+            let len = metadata.map_or(0, |metadata| metadata.len());
+            (path, modified, len)
+        })
+        .collect()
+}
+
+// Load the scripts and reload them whenever the directory changes
+pub fn init() {
+    let scripting: &ScriptingConfig = &config::get().scripting;
+    if !scripting.enabled {
+        return;
+    }
+    let dir = scripting.dir();
+    let interval = Duration::from_secs(scripting.reload_interval_secs.max(1));
+    let mut seen = fingerprint(&dir);
+    load(&dir);
+    tokio::spawn(async move {
+        while !shutdown::is_shutting_down() {
+            shutdown::sleep(interval).await;
+            let current = fingerprint(&dir);
+            if current != seen {
+                seen = current;
+                info!("Scripts in {} changed, reloading", dir.display());
+                load(&dir);
+            }
+        }
+    });
+}
+
+fn frames_of(result: Dynamic, original: &Frame) -> Result<Vec<Frame>, String> {
+    // () or false drops the frame
+    if result.is_unit() || result.as_bool() == Ok(false) {
+        return Ok(Vec::new());
+    }
+    let value = rhai::serde::from_dynamic::<Value>(&result).map_err(|e| e.to_string())?;
+    let items = match value {
+        Value::Array(items) => items,
+        item => vec![item],
+    };
+    items
+        .into_iter()
+        .map(|item| {
+            let Value::Object(mut fields) = item else {
+                return Err("on_frame must return a frame map, an array of them, () or false".to_string());
+            };
+            let mut number = |key: &str, default: u16| -> Result<u16, String> {
+                match fields.remove(key) {
+                    None => Ok(default),
+                    Some(value) =>
+                        value
+                            .as_u64()
+                            .and_then(|value| u16::try_from(value).ok())
+                            .ok_or_else(|| format!("{} must be an integer from 0 to 65535", key)),
+                }
+            };
+            let value = number("value", original.value)?;
+            let pid = number("pid", original.pid)?;
+            let mut text = |key: &str, default: &str| -> String {
+                match fields.remove(key) {
+                    Some(Value::String(text)) => text,
+                    _ => default.to_string(),
+                }
+            };
+            let mac = text("mac", &original.mac);
+            let device = text("device", &original.device);
+            Ok(Frame { mac, value, pid, device, extra: fields })
+        })
+        .collect()
+}
+
+fn run(script: &Script, frame: Frame) -> Vec<Frame> {
+    let result = rhai::serde
+        ::to_dynamic(&frame)
+        .and_then(|argument| {
+            engine().call_fn_with_options::<Dynamic>(
+                CallFnOptions::new().eval_ast(false),
+                &mut Scope::new(),
+                &script.ast,
+                "on_frame",
+                (argument,)
+            )
+        })
+        .map_err(|e| e.to_string())
+        .and_then(|result| frames_of(result, &frame));
+
+    // Only clients that receive this peripheral's data get events about it
+    for (event, data) in EMITTED.with(|emitted| std::mem::take(&mut *emitted.borrow_mut())) {
+        socketio::emit_script_event(format!("{}{}", EVENT_PREFIX, event), &frame.mac, data);
+    }
+    match result {
+        Ok(frames) => frames,
+        // A broken script must not swallow data; pass the frame on unchanged
+        Err(e) => {
+            if !script.failed.swap(true, Ordering::Relaxed) {
+                info!("Script {} failed (further errors are not logged until it is reloaded): {}", script.name, e);
+            }
+            vec![frame]
+        }
+    }
+}
+
+// Pass a frame through every script in turn
+pub fn process(frame: Frame) -> Vec<Frame> {
+    let scripts = SCRIPTS.read().unwrap_or_else(|e| e.into_inner());
+    let mut frames = vec![frame];
+    for script in scripts.iter() {
+        frames = frames
+            .into_iter()
+            .flat_map(|frame| run(script, frame))
+            .collect();
+    }
+    frames
+}
@@ -0,0 +1,161 @@
+use std::fs;
+use std::path::Path;
+use crate::{ config, instance, models::*, paths, serial };
+#[cfg(unix)]
+use crate::privileges;
+
+enum Outcome {
+    Ok,
+    Warn,
+    Fail,
+}
+
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    fn line(&mut self, outcome: Outcome, message: impl AsRef<str>) {
+        let label = match outcome {
+            Outcome::Ok => "  ok",
+            Outcome::Warn => "warn",
+            Outcome::Fail => {
+                self.failed = true;
+                "FAIL"
+            }
+        };
+        println!("[{}] {}", label, message.as_ref());
+    }
+}
+
+fn check_writable(report: &mut Report, label: &str, dir: &Path) {
+    let probe = dir.join(".self-check");
+    let result = fs::create_dir_all(dir).and_then(|_| fs::write(&probe, b"")).and_then(|_| fs::remove_file(&probe));
+    match result {
+        Ok(()) => report.line(Outcome::Ok, format!("{} {} is writable", label, dir.display())),
+        Err(e) => report.line(Outcome::Fail, format!("{} {} is not writable: {}", label, dir.display(), e)),
+    }
+}
+
+fn check_user(report: &mut Report) {
+    #[cfg(unix)]
+    {
+        let user = privileges::current_user();
+        let drop_to = config::get().privileges.user.as_deref();
+        match (paths::is_root(), drop_to) {
+            (true, Some(target)) if privileges::lookup_user(target).is_some() => {
+                report.line(Outcome::Ok, format!("Running as root, will drop privileges to {}", target));
+            }
+            (true, Some(target)) => {
+                report.line(Outcome::Fail, format!("User {} configured under [privileges] does not exist", target));
+            }
+            (true, None) => {
+                report.line(
+                    Outcome::Warn,
+                    "Running as root; run as a dedicated user (User= in the unit) or set [privileges] user"
+                );
+            }
+            (false, _) => report.line(Outcome::Ok, format!("Running as unprivileged user {}", user)),
+        }
+    }
+    #[cfg(not(unix))]
+    report.line(Outcome::Ok, "User checks are not applicable on this platform");
+}
+
+fn check_device(report: &mut Report) {
+    #[cfg(target_os = "linux")]
+    {
+        if Path::new(privileges::UDEV_RULES_PATH).exists() {
+            report.line(Outcome::Ok, format!("udev rule {} is installed", privileges::UDEV_RULES_PATH));
+        } else {
+            report.line(Outcome::Warn, format!("udev rule {} is not installed", privileges::UDEV_RULES_PATH));
+        }
+    }
+
+    let Some((pid, device)) = serial::find_connected_device_info(VID, PIDS) else {
+        report.line(Outcome::Warn, "No Snappy device connected; skipping device access check");
+        return;
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        // Open the node the way the agent will, so the sandbox's device policy is exercised too
+        let opened = fs::OpenOptions
+            ::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(&device);
+        match opened {
+            Ok(_) => report.line(Outcome::Ok, format!("Device {} (PID 0x{:04x}) can be opened", device, pid)),
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                report.line(Outcome::Ok, format!("Device {} is accessible and in use by a running agent", device));
+            }
+            // Only warnings: the agent starts anyway and keeps retrying the device, while
+            // a failing ExecStartPre= would leave systemd restarting it in a loop
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EACCES) | Some(libc::EPERM)) => {
+                report.line(Outcome::Warn, privileges::device_access_hint(&device));
+            }
+            Err(e) => report.line(Outcome::Warn, format!("Cannot open device {}: {}", device, e)),
+        }
+    }
+    #[cfg(not(unix))]
+    report.line(Outcome::Ok, format!("Device {} (PID 0x{:04x}) detected", device, pid));
+}
+
+// Report how the process is confined; under systemd the hardened unit should
+// leave it without capabilities and unable to gain new privileges
+#[cfg(target_os = "linux")]
+fn check_sandbox(report: &mut Report) {
+    let Ok(status) = fs::read_to_string("/proc/self/status") else {
+        return;
+    };
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .map(|value| value.trim().to_string())
+    };
+    let no_new_privs = field("NoNewPrivs:").is_some_and(|value| value == "1");
+    let no_capabilities = field("CapEff:").is_some_and(|value| value.chars().all(|c| c == '0'));
+    let seccomp = field("Seccomp:").is_some_and(|value| value != "0");
+    let under_systemd = std::env::var_os("INVOCATION_ID").is_some();
+
+    let summary = format!(
+        "Sandbox: no new privileges {}, effective capabilities {}, seccomp filter {}",
+        if no_new_privs { "yes" } else { "no" },
+        if no_capabilities { "none" } else { "present" },
+        if seccomp { "active" } else { "inactive" }
+    );
+    if !under_systemd || (no_new_privs && no_capabilities) {
+        report.line(Outcome::Ok, summary);
+    } else {
+        report.line(Outcome::Warn, format!("{} (the hardened unit sets NoNewPrivileges= and drops all capabilities)", summary));
+    }
+}
+
+// Verify the agent can do its job with the privileges it has. Returns false if
+// any check failed; the hardened unit runs this as ExecStartPre.
+pub fn run() -> bool {
+    let mut report = Report { failed: false };
+    println!("Snappy Web Agent {} self-check", env!("CARGO_PKG_VERSION"));
+
+    check_user(&mut report);
+    check_writable(&mut report, "Data directory", &paths::data_dir());
+    check_writable(&mut report, "Runtime directory", &paths::runtime_dir());
+    match instance::open_shared_lock() {
+        Ok((_, path)) => report.line(Outcome::Ok, format!("Instance lock {} can be opened", path.display())),
+        Err(e) =>
+            report.line(
+                Outcome::Fail,
+                format!("Instance lock {} cannot be opened: {}", instance::lock_path().display(), e)
+            ),
+    }
+    check_device(&mut report);
+    #[cfg(target_os = "linux")]
+    check_sandbox(&mut report);
+
+    !report.failed
+}
@@ -1,12 +1,106 @@
-use std::sync::{ Arc, Mutex };
+use std::collections::VecDeque;
+use std::sync::{ Arc, Mutex, OnceLock };
 #[cfg(target_os = "linux")]
 use std::fs; // for Linux get_serial
-#[cfg(not(target_os = "windows"))]
-use std::time::Duration;
+use std::time::{ Duration, Instant };
+use tokio::sync::watch;
 use crate::models::*;
 use crate::encryption::*;
-use tracing::info;
-use socketioxide::extract::SocketRef;
+use crate::{ filter, peripherals, scripting, shutdown, socketio };
+#[cfg(not(target_os = "windows"))]
+use crate::privileges;
+use crate::systemd::{ self, DeviceLoop };
+use tracing::{ info, warn };
+
+// Oldest queued commands are dropped beyond this while no dongle is open
+const MAX_PENDING_COMMANDS: usize = 16;
+
+// Lines waiting to be written to the open dongle, e.g. from trigger rules
+static PENDING_COMMANDS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+pub fn queue_command(command: &str) {
+    let mut pending = PENDING_COMMANDS.lock().unwrap_or_else(|e| e.into_inner());
+    if pending.len() >= MAX_PENDING_COMMANDS {
+        pending.pop_front();
+    }
+    pending.push_back(command.to_string());
+}
+
+fn next_command() -> Option<String> {
+    PENDING_COMMANDS.lock().unwrap_or_else(|e| e.into_inner()).pop_front()
+}
+
+// Undecodable frames are reported at most this often
+const DECODE_ERROR_INTERVAL: Duration = Duration::from_secs(10);
+
+// Opening a present but unavailable dongle is retried with this backoff
+const OPEN_RETRY_MIN: Duration = Duration::from_millis(500);
+const OPEN_RETRY_MAX: Duration = Duration::from_secs(5);
+
+// Where opening the dongle got to; start-snappy waits on it
+#[derive(Clone, Debug, PartialEq)]
+pub enum PortState {
+    Idle,
+    Opening,
+    Open,
+    Failed(ErrorCode, String),
+}
+
+static PORT_STATE: OnceLock<watch::Sender<PortState>> = OnceLock::new();
+
+fn port_state() -> &'static watch::Sender<PortState> {
+    PORT_STATE.get_or_init(|| watch::channel(PortState::Idle).0)
+}
+
+// Failures are sent to every client as `snappy-error`, once per change
+pub fn set_port_state(state: PortState, device: Option<&str>) {
+    let changed = port_state().send_if_modified(|current| {
+        if *current == state {
+            return false;
+        }
+        *current = state.clone();
+        true
+    });
+    if let (true, PortState::Failed(code, message)) = (changed, state) {
+        socketio::report_error(code, message, device);
+    }
+}
+
+// An open port whose frames cannot be decrypted is reported as failed
+fn open_state(key_available: bool) -> PortState {
+    if key_available {
+        PortState::Open
+    } else {
+        let message = "the device serial number could not be read, so its data cannot be decrypted";
+        PortState::Failed(ErrorCode::KeyUnavailable, message.to_string())
+    }
+}
+
+// Until the dongle is open, or why it could not be opened. A missing device or key
+// fails at once; the reader keeps retrying other failures, so they only count at the timeout.
+pub async fn wait_for_port(timeout: Duration) -> Result<(), (ErrorCode, String)> {
+    let mut receiver = port_state().subscribe();
+    let waited = tokio::time::timeout(
+        timeout,
+        receiver.wait_for(|state| {
+            matches!(
+                state,
+                PortState::Open | PortState::Failed(ErrorCode::NoDevice | ErrorCode::KeyUnavailable, _)
+            )
+        })
+    ).await;
+    if let Ok(Ok(state)) = &waited {
+        return match &**state {
+            PortState::Failed(code, message) => Err((*code, message.clone())),
+            _ => Ok(()),
+        };
+    }
+    drop(waited);
+    match &*receiver.borrow() {
+        PortState::Failed(code, message) => Err((*code, message.clone())),
+        _ => Err((ErrorCode::Timeout, format!("the device did not open within {}s", timeout.as_secs()))),
+    }
+}
 
 // Linux-only helper to fetch serial via sysfs
 #[cfg(target_os = "linux")]
@@ -150,6 +244,29 @@ fn get_serial(_dev: &str) -> Option<String> {
     None
 }
 
+// Serial number of the dongle behind `port`
+#[cfg(not(target_os = "windows"))]
+pub fn device_serial(port: &str) -> Option<String> {
+    let ports = serialport::available_ports().unwrap_or_default();
+    let from_port = ports
+        .iter()
+        .find(|available_port| available_port.port_name == port)
+        .and_then(|available_port| match &available_port.port_type {
+            serialport::SerialPortType::UsbPort(info) => info.serial_number.clone(),
+            _ => None,
+        });
+    // Same fallback as when keying the decryption
+    match from_port {
+        Some(serial) if serial != "6" => Some(serial),
+        _ => get_serial(port),
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub fn device_serial(_port: &str) -> Option<String> {
+    None
+}
+
 // New function to check if any of the supported devices is connected
 pub fn is_any_device_connected(vid: u16, pids: &[u16]) -> bool {
     for &pid in pids {
@@ -192,19 +309,21 @@ pub fn find_connected_device_info(vid: u16, pids: &[u16]) -> Option<(u16, String
     {
         let ports = serialport::available_ports().unwrap_or_else(|_| vec![]);
         for available_port in ports {
-            if let serialport::SerialPortType::UsbPort(info) = &available_port.port_type {
-                if info.vid == vid && pids.contains(&info.pid) {
-                    return Some((info.pid, available_port.port_name.clone()));
-                }
+            if
+                let serialport::SerialPortType::UsbPort(info) = &available_port.port_type &&
+                info.vid == vid &&
+                pids.contains(&info.pid)
+            {
+                return Some((info.pid, available_port.port_name.clone()));
             }
         }
         None
     }
 }
 
-pub async fn start_snappy_with_socket(_socket: SocketRef) {
-    // Import the socketio functions
-    use crate::socketio::is_snappy_collecting;
+// Runs until collection `generation` is stopped or superseded by a newer one
+pub async fn start_snappy(generation: u64) {
+    let is_snappy_collecting = move || socketio::is_collection_current(generation);
 
     let hash_key = Arc::new(Mutex::new(Vec::<u8>::new()));
     let current_device_pid = Arc::new(Mutex::new(None::<u16>));
@@ -223,6 +342,7 @@ pub async fn start_snappy_with_socket(_socket: SocketRef) {
                 info!("Snappy data collection stopped");
                 break;
             }
+            systemd::device_heartbeat(DeviceLoop::Supervisor);
             let mut detected_device: Option<(String, u16)> = None;
 
             #[cfg(target_os = "windows")]
@@ -316,9 +436,13 @@ pub async fn start_snappy_with_socket(_socket: SocketRef) {
                 }
             }
 
+            if detected_device.is_none() {
+                set_port_state(PortState::Failed(ErrorCode::NoDevice, "no supported device is connected".to_string()), None);
+            }
             if detected_device != last_connected_device {
                 last_connected_device = detected_device.clone();
                 if let Some((device_name, pid)) = detected_device {
+                    set_port_state(PortState::Opening, Some(&device_name));
                     let _ = tx.send((device_name, pid)).await;
                 } else {
                     let _ = tx.send((String::new(), 0)).await;
@@ -331,7 +455,8 @@ pub async fn start_snappy_with_socket(_socket: SocketRef) {
     let hash_key_for_task = Arc::clone(&hash_key);
     let _current_device_pid_for_task = Arc::clone(&current_device_pid);
 
-    tokio::spawn(async move {
+    // Tracked so shutdown waits for the port to be closed
+    shutdown::device_tasks().spawn(async move {
         while let Some((path, device_pid)) = rx.recv().await {
             // Check if we should stop collecting
             if !is_snappy_collecting() {
@@ -345,6 +470,10 @@ pub async fn start_snappy_with_socket(_socket: SocketRef) {
                 let serial_number = hash_key_for_task.lock().unwrap().clone();
                 hash_serial(&serial_number, &mut hash);
                 let counter = 0x0u32;
+                // Without the serial number frames cannot be decrypted; the port is
+                // opened anyway, but reported as KEY_UNAVAILABLE rather than open
+                let key_available = !serial_number.is_empty();
+                let mut decode_errors = DecodeErrors::default();
 
                 info!("Device connected for snappy data collection - PID: 0x{:04x}", device_pid);
 
@@ -357,6 +486,14 @@ pub async fn start_snappy_with_socket(_socket: SocketRef) {
                             info!("Stopping snappy data collection");
                             break;
                         }
+                        // The supervisor saw the device go away or change
+                        if !rx.is_empty() {
+                            break;
+                        }
+                        systemd::device_heartbeat(DeviceLoop::Reader);
+                        while let Some(command) = next_command() {
+                            info!("Dropping device command {:?}: not supported over USB", command);
+                        }
 
                         // Establish session if missing
                         if session.is_none() {
@@ -368,10 +505,12 @@ pub async fn start_snappy_with_socket(_socket: SocketRef) {
                                         s.endpoint,
                                         s.device_pid
                                     );
+                                    set_port_state(open_state(key_available), Some(&path));
                                     session = Some(s);
                                 }
                                 Err(e) => {
                                     info!("Failed to open USB session: {}", e);
+                                    set_port_state(PortState::Failed(ErrorCode::PortOpenFailed, e), Some(&path));
                                     tokio::time::sleep(
                                         tokio::time::Duration::from_millis(500)
                                     ).await;
@@ -384,7 +523,9 @@ pub async fn start_snappy_with_socket(_socket: SocketRef) {
                             match read_snappy_data_via_usb(s, &hash, counter) {
                                 Some(Ok(data)) => {
                                     // Pass the device PID to the processing function
-                                    process_serial_message_with_emit(&data, s.device_pid);
+                                    if !process_serial_message_with_emit(&data, s.device_pid, &path) {
+                                        decode_errors.record(&path);
+                                    }
                                 }
                                 Some(Err(e)) => {
                                     info!("USB read error: {}", e);
@@ -401,60 +542,109 @@ pub async fn start_snappy_with_socket(_socket: SocketRef) {
                             }
                         }
                     }
+                    systemd::reader_idle();
                 }
 
                 #[cfg(not(target_os = "windows"))]
                 {
                     // For other OS, use serial port communication
-                    match serialport::new(&path, 230400).timeout(Duration::from_secs(2)).open() {
-                        Ok(mut port) => {
-                            info!("Device connected for snappy data collection - PID: 0x{:04x}", device_pid);
-                            let mut buffer = [0; 64];
-                            let mut data_buffer: Vec<u8> = Vec::new();
-                            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-
-                            loop {
-                                if !is_snappy_collecting() {
-                                    info!("Stopping snappy data collection");
-                                    break;
+                    // Exclusive access (TIOCEXCL + flock) keeps a second process off the dongle
+                    // Busy, not permitted or failing ports are retried for as long as the
+                    // device is present; the supervisor only reports changes
+                    let mut retry_delay = OPEN_RETRY_MIN;
+                    let mut opened = None;
+                    while opened.is_none() && is_snappy_collecting() && rx.is_empty() {
+                        let port_result = serialport
+                            ::new(&path, 230400)
+                            .timeout(Duration::from_secs(2))
+                            .exclusive(true)
+                            .open();
+                        let (code, message) = match port_result {
+                            Ok(port) => {
+                                opened = Some(port);
+                                continue;
+                            }
+                            Err(e) if is_port_busy(&e) => {
+                                info!("Serial port {} is already in use by another process: {}", path, e);
+                                (ErrorCode::PortBusy, format!("{} is already in use by another process", path))
+                            }
+                            Err(e) if privileges::is_permission_denied(&e) => {
+                                let hint = privileges::device_access_hint(&path);
+                                info!("{}", hint);
+                                (ErrorCode::PermissionDenied, hint)
+                            }
+                            Err(e) => {
+                                info!("Failed to open serial port: {}", e);
+                                (ErrorCode::PortOpenFailed, e.to_string())
+                            }
+                        };
+                        set_port_state(PortState::Failed(code, message), Some(&path));
+                        info!("Retrying {} in {:?}", path, retry_delay);
+                        tokio::time::sleep(retry_delay).await;
+                        retry_delay = (retry_delay * 2).min(OPEN_RETRY_MAX);
+                    }
+                    if let Some(mut port) = opened {
+                        info!("Device connected for snappy data collection - PID: 0x{:04x}", device_pid);
+                        set_port_state(open_state(key_available), Some(&path));
+                        let mut buffer = [0; 64];
+                        let mut data_buffer: Vec<u8> = Vec::new();
+                        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+                        loop {
+                            if !is_snappy_collecting() {
+                                info!("Stopping snappy data collection");
+                                break;
+                            }
+                            // The supervisor saw the device go away or change
+                            if !rx.is_empty() {
+                                break;
+                            }
+                            systemd::device_heartbeat(DeviceLoop::Reader);
+
+                            // Commands are written as plain lines terminated by CRLF
+                            while let Some(command) = next_command() {
+                                let line = format!("{}\r\n", command);
+                                match port.write_all(line.as_bytes()) {
+                                    Ok(()) => info!("Sent device command {:?} to {}", command, path),
+                                    Err(e) => info!("Failed to send device command {:?}: {}", command, e),
                                 }
+                            }
 
-                                match port.read(&mut buffer) {
-                                    Ok(bytes_read) if bytes_read > 0 => {
-                                        info!("Read {} bytes from serial port (PID: 0x{:04x})", bytes_read, device_pid);
-                                        data_buffer.extend_from_slice(&buffer[..bytes_read]);
-                                        while
-                                            let Some(pos) = data_buffer
-                                                .windows(2)
-                                                .position(|window| window == b"\r\n")
-                                        {
-                                            let message = &data_buffer[..pos];
-                                            let mut decrypted = vec![0u8; data_buffer[..pos].len()];
-                                            chacha20_decrypt(
-                                                &hash,
-                                                counter,
-                                                message,
-                                                &mut decrypted
-                                            );
-
-                                            // Process and emit data with device PID
-                                            process_serial_message_with_emit(decrypted.as_slice(), device_pid);
-
-                                            data_buffer.drain(..pos + 2);
+                            match port.read(&mut buffer) {
+                                Ok(bytes_read) if bytes_read > 0 => {
+                                    info!("Read {} bytes from serial port (PID: 0x{:04x})", bytes_read, device_pid);
+                                    data_buffer.extend_from_slice(&buffer[..bytes_read]);
+                                    while
+                                        let Some(pos) = data_buffer
+                                            .windows(2)
+                                            .position(|window| window == b"\r\n")
+                                    {
+                                        let message = &data_buffer[..pos];
+                                        let mut decrypted = vec![0u8; data_buffer[..pos].len()];
+                                        chacha20_decrypt(
+                                            &hash,
+                                            counter,
+                                            message,
+                                            &mut decrypted
+                                        );
+
+                                        // Process and emit data with device PID
+                                        if !process_serial_message_with_emit(decrypted.as_slice(), device_pid, &path) {
+                                            decode_errors.record(&path);
                                         }
+
+                                        data_buffer.drain(..pos + 2);
                                     }
-                                    _ => {
-                                        tokio::time::sleep(
-                                            tokio::time::Duration::from_millis(10)
-                                        ).await;
-                                    }
+                                }
+                                _ => {
+                                    tokio::time::sleep(
+                                        tokio::time::Duration::from_millis(10)
+                                    ).await;
                                 }
                             }
                         }
-                        Err(e) => {
-                            info!("Failed to open serial port: {}", e);
-                        }
                     }
+                    systemd::reader_idle();
                 }
             }
         }
@@ -463,10 +653,42 @@ pub async fn start_snappy_with_socket(_socket: SocketRef) {
     serial_port_checker_t.await.expect("Failed to start serial port checker for snappy");
 }
 
-fn process_serial_message_with_emit(message: &[u8], device_pid: u16) {
+// EBUSY from TIOCEXCL or EWOULDBLOCK from flock mean someone else has the port open
+#[cfg(not(target_os = "windows"))]
+fn is_port_busy(error: &serialport::Error) -> bool {
+    if let serialport::ErrorKind::Io(kind) = error.kind() {
+        return kind == std::io::ErrorKind::ResourceBusy || kind == std::io::ErrorKind::WouldBlock;
+    }
+    error.description.contains("busy") || error.description.contains("temporarily unavailable")
+}
+
+// Frames that do not decrypt to a snap message, reported now and then
+#[derive(Default)]
+struct DecodeErrors {
+    count: u64,
+    reported: Option<Instant>,
+}
+
+impl DecodeErrors {
+    fn record(&mut self, device: &str) {
+        self.count += 1;
+        if self.reported.is_some_and(|reported| reported.elapsed() < DECODE_ERROR_INTERVAL) {
+            return;
+        }
+        self.reported = Some(Instant::now());
+        let message = format!("{} frame(s) could not be decoded", self.count);
+        info!("{} from {}", message, device);
+        socketio::report_error(ErrorCode::DecodeError, message, Some(device));
+        self.count = 0;
+    }
+}
+
+// False when the message is not a snap frame
+fn process_serial_message_with_emit(message: &[u8], device_pid: u16, device: &str) -> bool {
     use crate::socketio::emit_snap_data;
 
-    if message.len() >= 14 && message[..7] == EXPECTED_PREFIX {
+    // Prefix (7 bytes), MAC (6) and value (2); a shorter frame has no value to read
+    if message.len() >= 15 && message[..7] == EXPECTED_PREFIX {
         let mac_bytes = &message[7..13]; // 6 bytes for MAC
         let dev_value = &message[13..15]; // 2 bytes for the device value
 
@@ -481,10 +703,41 @@ fn process_serial_message_with_emit(message: &[u8], device_pid: u16) {
         // Convert the 2 bytes into a short value in decimal
         let device_value = ((dev_value[0] as u16) << 8) | (dev_value[1] as u16);
 
-        // Emit the data via socket with PID information
-        emit_snap_data(mac_str.to_string(), device_value, device_pid);
-
-        info!("Emitted snap data - MAC: {}, value: {}, PID: 0x{:04x}", mac_str, device_value, device_pid);
+        // Filtered peripherals are not tracked either
+        if !filter::allows(mac_str) {
+            return true;
+        }
+        peripherals::observe(mac_str, device_value, device_pid, device);
+
+        // Scripts can drop, change or multiply the frame
+        let frame = scripting::Frame {
+            mac: mac_str.to_string(),
+            value: device_value,
+            pid: device_pid,
+            device: device.to_string(),
+            extra: Default::default(),
+        };
+        for mut frame in scripting::process(frame) {
+            // A script may have rewritten the MAC: spell it the way the filter
+            // and clients expect, and drop the frame if it is no MAC at all
+            if frame.mac != mac_str {
+                let Some(mac) = filter::parse_mac(&frame.mac) else {
+                    warn!("Dropping script output with invalid MAC {:?}", frame.mac);
+                    continue;
+                };
+                if !filter::allows(&mac) {
+                    continue;
+                }
+                frame.mac = mac;
+            }
+            // Emit the data via socket with PID information
+            let extra = (!frame.extra.is_empty()).then_some(frame.extra);
+            emit_snap_data(frame.mac.clone(), frame.value, frame.pid, &frame.device, extra);
+            info!("Emitted snap data - MAC: {}, value: {}, PID: 0x{:04x}", frame.mac, frame.value, frame.pid);
+        }
+        true
+    } else {
+        false
     }
 }
 
@@ -521,10 +774,12 @@ pub fn is_device_connected(vid: u16, pid: u16) -> bool {
     {
         let ports = serialport::available_ports().unwrap_or_else(|_| vec![]);
         for available_port in ports {
-            if let serialport::SerialPortType::UsbPort(info) = &available_port.port_type {
-                if info.vid == vid && info.pid == pid {
-                    return true;
-                }
+            if
+                let serialport::SerialPortType::UsbPort(info) = &available_port.port_type &&
+                info.vid == vid &&
+                info.pid == pid
+            {
+                return true;
             }
         }
         false
@@ -0,0 +1,508 @@
+use std::net::{ IpAddr, SocketAddr };
+use axum::{
+    body::Body,
+    extract::{ Path, Query },
+    http::{ header, HeaderMap, StatusCode },
+    response::{ IntoResponse, Response },
+    routing::{ get, post, put },
+    Json,
+};
+use chrono::Utc;
+use socket2::{ Domain, Protocol, Socket, Type };
+use socketioxide::SocketIo;
+use tracing::info;
+use tower_http::cors::{ CorsLayer, Any };
+use crate::{ config, discovery, filter, instance, models::*, peripherals, privileges, protocol, rules, scripting, sessions, shutdown, socketio, store, systemd, tls };
+
+// Bytes of an export buffered between the writing task and the response
+const EXPORT_BUFFER: usize = 64 * 1024;
+
+fn build_app() -> axum::Router {
+    let (socketio_layer, io) = SocketIo::new_layer();
+    io.ns("/", socketio::on_connect);
+    io.ns(protocol::V2_NAMESPACE, socketio::on_connect_v2);
+    socketio::set_io(io);
+    let cors = CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any);
+    axum::Router
+        ::new()
+        .route(
+            "/",
+            get(|| async { "alive" })
+        )
+        .route(
+            "/discovery",
+            get(|| async { Json(discovery::current().cloned()) })
+        )
+        .route("/history", get(history))
+        .route("/sessions", get(list_sessions).post(start_session))
+        .route("/sessions/stop", post(stop_session))
+        .route("/sessions/{id}", get(get_session))
+        .route("/sessions/{id}/export/{format}", get(export_session))
+        .route("/peripherals", get(list_peripherals))
+        .route("/peripherals/{mac}", get(get_peripheral))
+        .route("/peripherals/{mac}/alias", put(set_peripheral_alias))
+        .route("/snapshot", get(snapshot))
+        .route("/filter", get(get_mac_filter).put(set_mac_filter))
+        .route("/shutdown", post(request_shutdown))
+        .layer(socketio_layer)
+        .layer(cors)
+}
+
+async fn history(Query(query): Query<HistoryQuery>) -> (StatusCode, Json<HistoryResponse>) {
+    let response = store::history(query).await;
+    let status = match &response {
+        response if response.success => StatusCode::OK,
+        _ if !store::is_enabled() => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    (status, Json(response))
+}
+
+// Used by `--replace` when the running agent cannot be signalled directly
+async fn request_shutdown(headers: HeaderMap) -> StatusCode {
+    let token = headers.get("x-shutdown-token").and_then(|value| value.to_str().ok());
+    if !token.is_some_and(instance::shutdown_token_matches) {
+        return StatusCode::FORBIDDEN;
+    }
+    shutdown::trigger("replaced by another agent");
+    StatusCode::ACCEPTED
+}
+
+async fn list_sessions() -> Json<SessionListResponse> {
+    Json(sessions::list_response())
+}
+
+async fn start_session(Json(request): Json<StartRecording>) -> (StatusCode, Json<SessionResponse>) {
+    let response = sessions::response("start-recording", sessions::start(request));
+    let status = if response.success { StatusCode::CREATED } else { StatusCode::CONFLICT };
+    (status, Json(response))
+}
+
+async fn stop_session() -> (StatusCode, Json<SessionResponse>) {
+    let response = sessions::response("stop-recording", sessions::stop().await);
+    let status = if response.success { StatusCode::OK } else { StatusCode::CONFLICT };
+    (status, Json(response))
+}
+
+async fn get_session(Path(id): Path<String>) -> (StatusCode, Json<SessionResponse>) {
+    let result = sessions::get(&id).ok_or_else(|| format!("unknown session {}", id));
+    let response = sessions::response("get-recording", result);
+    let status = if response.success { StatusCode::OK } else { StatusCode::NOT_FOUND };
+    (status, Json(response))
+}
+
+// Streamed: a blocking task writes the export into a pipe the response body reads from
+async fn export_session(Path((id, format)): Path<(String, String)>) -> Response {
+    let Some(session) = sessions::get(&id) else {
+        return (StatusCode::NOT_FOUND, format!("unknown session {}", id)).into_response();
+    };
+    let content_type = match format.as_str() {
+        "csv" => "text/csv; charset=utf-8",
+        "jsonl" => "application/x-ndjson",
+        "parquet" => "application/vnd.apache.parquet",
+        other => {
+            let message = format!("unsupported export format '{}' (use csv, jsonl or parquet)", other);
+            return (StatusCode::BAD_REQUEST, message).into_response();
+        }
+    };
+    let disposition = format!("attachment; filename=\"{}.{}\"", session.id, format);
+
+    let (reader, writer) = tokio::io::duplex(EXPORT_BUFFER);
+    tokio::task::spawn_blocking(move || {
+        let out = std::io::BufWriter::new(tokio_util::io::SyncIoBridge::new(writer));
+        // The status is already sent; a failure just ends the download early
+        if let Err(e) = sessions::export(&id, &format, out) {
+            info!("Export of session {} as {} failed: {}", id, format, e);
+        }
+    });
+    let body = Body::from_stream(tokio_util::io::ReaderStream::new(reader));
+    ([(header::CONTENT_TYPE, content_type.to_string()), (header::CONTENT_DISPOSITION, disposition)], body).into_response()
+}
+
+async fn list_peripherals() -> Json<PeripheralsResponse> {
+    Json(peripherals::response(Ok(peripherals::list())))
+}
+
+async fn get_peripheral(Path(mac): Path<String>) -> (StatusCode, Json<PeripheralsResponse>) {
+    let result = peripherals::get(&mac).map(|peripheral| vec![peripheral]).ok_or_else(|| format!("unknown peripheral {}", mac));
+    let response = peripherals::response(result);
+    let status = if response.success { StatusCode::OK } else { StatusCode::NOT_FOUND };
+    (status, Json(response))
+}
+
+async fn set_peripheral_alias(
+    Path(mac): Path<String>,
+    Json(request): Json<PeripheralAlias>
+) -> (StatusCode, Json<PeripheralsResponse>) {
+    let result = peripherals::set_alias(&mac, request.alias).map(|peripheral| vec![peripheral]);
+    let mut response = peripherals::response(result);
+    response.command = "set-peripheral-alias".to_string();
+    let status = if response.success { StatusCode::OK } else { StatusCode::NOT_FOUND };
+    (status, Json(response))
+}
+
+async fn snapshot() -> Json<SnapshotResponse> {
+    Json(socketio::snapshot(None))
+}
+
+async fn get_mac_filter() -> Json<MacFilterResponse> {
+    Json(filter::response("get-mac-filter", Ok(filter::current())))
+}
+
+async fn set_mac_filter(Json(update): Json<MacFilterUpdate>) -> (StatusCode, Json<MacFilterResponse>) {
+    let response = filter::response("set-mac-filter", filter::update(update));
+    let status = if response.success { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+    (status, Json(response))
+}
+
+fn bind_listener(addr: SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Keep "::" from also claiming the IPv4 port so both families can be listed
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    // On Windows SO_REUSEADDR would let another process steal a bound port
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+// Drop addresses that do not exist on this host (e.g. ::1 with IPv6 disabled)
+// so they do not make every port look busy
+fn usable_addresses(addrs: &[IpAddr]) -> Vec<IpAddr> {
+    let mut usable = Vec::new();
+    for &ip in addrs {
+        match bind_listener(SocketAddr::new(ip, 0)) {
+            Ok(_) => usable.push(ip),
+            Err(e) => {
+                info!("Skipping listen address {}: {}", ip, e);
+            }
+        }
+    }
+    usable
+}
+
+// Bind every address on the first port where all of them are free and keep the
+// listeners, so nothing can grab the port between discovery and serving
+pub fn bind_available_port(
+    addrs: &[IpAddr],
+    start_port: u16,
+    max_attempts: u16
+) -> Result<(u16, Vec<std::net::TcpListener>), Box<dyn std::error::Error>> {
+    'ports: for port in start_port..start_port + max_attempts {
+        let mut listeners = Vec::new();
+        for &ip in addrs {
+            match bind_listener(SocketAddr::new(ip, port)) {
+                Ok(listener) => listeners.push(listener),
+                Err(_) => {
+                    info!("Port {} is not available on {}, trying next...", port, ip);
+                    continue 'ports;
+                }
+            }
+        }
+        info!("Found available port: {}", port);
+        return Ok((port, listeners));
+    }
+    Err(
+        format!(
+            "No available port found in range {}..{}",
+            start_port,
+            start_port + max_attempts
+        ).into()
+    )
+}
+
+// `/discovery` on a port of its own, so clients have one fixed place to ask even
+// when the main listener had to move past its first port
+fn serve_discovery(addrs: &[IpAddr], port: u16) -> Option<u16> {
+    let mut listeners = Vec::new();
+    for &ip in addrs {
+        match bind_listener(SocketAddr::new(ip, port)) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                info!(
+                    "DISCOVERY UNAVAILABLE: port {} on {} is taken ({}); clients cannot find this agent at http://localhost:{}/discovery and must read {}",
+                    port,
+                    ip,
+                    e,
+                    port,
+                    discovery::discovery_path().display()
+                );
+                return None;
+            }
+        }
+    }
+    let cors = CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any);
+    let app = axum::Router
+        ::new()
+        .route(
+            "/discovery",
+            get(|| async { Json(discovery::current().cloned()) })
+        )
+        .layer(cors);
+    for listener in listeners {
+        let Ok(listener) = tokio::net::TcpListener::from_std(listener) else {
+            continue;
+        };
+        info!("Serving discovery on {:?}", listener.local_addr());
+        let server = axum::serve(listener, app.clone()).with_graceful_shutdown(shutdown::token().cancelled_owned());
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                info!("Discovery listener failed: {}", e);
+            }
+        });
+    }
+    Some(port)
+}
+
+#[cfg(unix)]
+fn serve_unix_socket(app: axum::Router, path: &std::path::Path, group: Option<&str>) -> std::io::Result<()> {
+    use std::os::unix::fs::{ FileTypeExt, PermissionsExt };
+
+    let gid = match group {
+        Some(group) =>
+            Some(
+                privileges
+                    ::lookup_group(group)
+                    .ok_or_else(|| std::io::Error::other(format!("unknown group {}", group)))?
+            ),
+        None => None,
+    };
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // A socket left behind by a previous run would make bind fail; anything
+    // else at the path is not ours to delete
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::other(format!("{} exists and is not a socket", path.display())));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    // Connecting needs write access: the agent's user and the configured group only
+    std::os::unix::fs::chown(path, None, gid)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660))?;
+
+    info!("Listening on Unix socket {}", path.display());
+    serve_unix_listener(app, listener, Some(path.to_path_buf()))
+}
+
+// `cleanup` is the socket path to remove on shutdown; None for sockets owned by systemd
+#[cfg(unix)]
+fn serve_unix_listener(
+    app: axum::Router,
+    listener: std::os::unix::net::UnixListener,
+    cleanup: Option<std::path::PathBuf>
+) -> std::io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::UnixListener::from_std(listener)?;
+    tokio::spawn(async move {
+        let server = axum::serve(listener, app).with_graceful_shutdown(shutdown::token().cancelled_owned());
+        if let Err(e) = server.await {
+            info!("Unix socket listener failed: {}", e);
+        }
+        if let Some(path) = cleanup {
+            let _ = std::fs::remove_file(&path);
+        }
+    });
+    Ok(())
+}
+
+pub async fn start_server() {
+    // Only one agent may own the dongle, whichever user or service started it
+    let mut instance = match instance::acquire(config::get().instance.on_conflict).await {
+        Ok(Some(guard)) => guard,
+        Ok(None) => {
+            return;
+        }
+        Err(e) => {
+            panic!("Failed to take the single-instance lock {}: {}", instance::lock_path().display(), e);
+        }
+    };
+
+    shutdown::listen_for_signals();
+
+    let app = build_app();
+    let server_config = &config::get().server;
+
+    // Sockets passed in by systemd socket activation replace our own binding
+    let activated = systemd::take_listen_fds();
+    let addrs = usable_addresses(&server_config.listen);
+
+    let listeners = if !activated.http.is_empty() {
+        activated.http
+    } else {
+        if addrs.is_empty() {
+            panic!("None of the configured listen addresses can be bound");
+        }
+        // Try to find an available port starting from 8436
+        let (_, listeners) = bind_available_port(
+            &addrs,
+            server_config.port,
+            server_config.port_attempts
+        ).unwrap_or_else(|_| {
+            panic!("Could not find an available port");
+        });
+        listeners
+    };
+    let local_addrs: Vec<SocketAddr> = listeners
+        .iter()
+        .filter_map(|listener| listener.local_addr().ok())
+        .collect();
+    let Some(port) = local_addrs.first().map(|addr| addr.port()) else {
+        panic!("No HTTP listening socket available");
+    };
+
+    let tls_config = &config::get().tls;
+    let tls_listeners = if !activated.tls.is_empty() {
+        Some(activated.tls)
+    } else if tls_config.enabled {
+        match bind_available_port(&addrs, tls_config.port, server_config.port_attempts) {
+            Ok((_, tls_listeners)) => Some(tls_listeners),
+            Err(e) => {
+                info!("Not starting HTTPS/WSS listener: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut tls_port = None;
+    if let Some(tls_listeners) = tls_listeners {
+        match tls::load_config(&tls_config.cert_dir()).await {
+            Ok(rustls_config) => {
+                tls_port = tls_listeners
+                    .first()
+                    .and_then(|listener| listener.local_addr().ok())
+                    .map(|addr| addr.port());
+                let tls_app = app.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = tls::serve_tls(tls_app, tls_listeners, rustls_config, tls_config.cert_dir()).await {
+                        info!("HTTPS/WSS listener failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                info!("Not starting HTTPS/WSS listener: {}", e);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    let activated_unix_path = activated.unix
+        .first()
+        .and_then(|listener| listener.local_addr().ok())
+        .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()));
+    #[cfg(not(unix))]
+    let activated_unix_path: Option<String> = None;
+    #[cfg(unix)]
+    for listener in activated.unix {
+        if let Err(e) = serve_unix_listener(app.clone(), listener, None) {
+            info!("Failed to serve activated Unix socket: {}", e);
+        }
+    }
+    if let Some(path) = &server_config.unix_socket && activated_unix_path.is_none() {
+        #[cfg(unix)]
+        if let Err(e) = serve_unix_socket(app.clone(), path, server_config.unix_socket_group.as_deref()) {
+            info!("Failed to listen on Unix socket {}: {}", path.display(), e);
+        }
+        #[cfg(not(unix))]
+        info!("Unix socket listener {} is not supported on this platform", path.display());
+    }
+
+    let discovery_port = match server_config.discovery_port {
+        0 => None,
+        // Already served by the main listener
+        discovery_port if discovery_port == port => Some(port),
+        discovery_port => serve_discovery(&addrs, discovery_port),
+    };
+
+    let discovery_info = DiscoveryInfo {
+        pid: std::process::id(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        port,
+        tls_port,
+        discovery_port,
+        addresses: local_addrs.iter().map(|addr| addr.ip().to_string()).collect(),
+        unix_socket: activated_unix_path.or_else(||
+            server_config.unix_socket.as_ref().map(|p| p.display().to_string())
+        ),
+        started_at: Utc::now().to_rfc3339(),
+    };
+    if let Err(e) = instance.record(&discovery_info) {
+        info!("Failed to record instance details in lock file: {}", e);
+    }
+    if let Err(e) = instance::issue_shutdown_token() {
+        info!("Failed to write shutdown token, --replace will not work: {}", e);
+    }
+    if let Err(e) = discovery::publish(discovery_info) {
+        info!("Failed to publish discovery file: {}", e);
+    }
+
+    // Everything that may need root (ports, certificates, runtime files) is done
+    if let Some(user) = &config::get().privileges.user && let Err(e) = privileges::drop_privileges(user) {
+        panic!("Failed to drop privileges to {}: {}", user, e);
+    }
+    if let Err(e) = store::init() {
+        info!("History store disabled: {}", e);
+    }
+    peripherals::spawn_monitor();
+    scripting::init();
+    // Rules work without a browser tab, so they need the stream from the start
+    if rules::any() {
+        socketio::start_collecting();
+        rules::spawn_hold_timer();
+    }
+
+    // The watchdog probes the same listener clients use
+    let probe_addr = local_addrs[0];
+
+    let mut servers = Vec::new();
+    for listener in listeners {
+        let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+        let addr = listener.local_addr().unwrap();
+        info!("Starting the device on {}...", addr);
+        let server = axum::serve(listener, app.clone()).with_graceful_shutdown(
+            shutdown::token().cancelled_owned()
+        );
+        servers.push(
+            tokio::spawn(async move {
+                if let Err(e) = server.await {
+                    info!("Listener on {} failed: {}", addr, e);
+                    shutdown::trigger("listener failed");
+                }
+            })
+        );
+    }
+
+    systemd::ready(port, probe_addr);
+
+    shutdown::token().cancelled().await;
+    info!("Shutting down...");
+    systemd::stopping();
+    let drained = tokio::time::timeout(shutdown::SHUTDOWN_TIMEOUT, async {
+        socketio::shutdown().await;
+        shutdown::device_tasks().close();
+        shutdown::device_tasks().wait().await;
+        for server in servers {
+            let _ = server.await;
+        }
+    }).await;
+    if drained.is_err() {
+        info!("Shutdown did not finish within {:?}, exiting anyway", shutdown::SHUTDOWN_TIMEOUT);
+    }
+
+    // Devices are stopped, so the session has every event and can be closed
+    // as stopped rather than left to show up as interrupted
+    if sessions::active_id().is_some() && let Err(e) = sessions::stop().await {
+        info!("Failed to stop the recording session: {}", e);
+    }
+
+    peripherals::save();
+    discovery::withdraw();
+    info!("Snappy Web Agent stopped");
+}
@@ -0,0 +1,427 @@
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ BufRead, BufReader, LineWriter, Write };
+use std::path::PathBuf;
+use std::sync::{ atomic::{ AtomicBool, AtomicU64, Ordering }, mpsc, Arc, Mutex, OnceLock };
+use chrono::Utc;
+use parquet::{
+    basic::Compression,
+    data_type::{ ByteArray, ByteArrayType, Int32Type, Int64Type },
+    file::{ properties::WriterProperties, writer::SerializedFileWriter },
+    schema::parser::parse_message_type,
+};
+use tracing::info;
+use crate::{ models::*, paths };
+
+// Rows written per parquet row group, so exports never hold a whole session
+const PARQUET_ROW_GROUP: usize = 8192;
+
+struct ActiveSession {
+    info: SessionInfo,
+}
+
+// Work for the writer thread, in order: events always land in the file that was
+// open when they were captured
+enum SessionWrite {
+    Open(String, File),
+    Event(SessionEvent),
+    // Acknowledged once everything before them is on disk
+    Flush(mpsc::Sender<()>),
+    Close(mpsc::Sender<()>),
+}
+
+// At most one session records at a time
+static ACTIVE: OnceLock<Mutex<Option<ActiveSession>>> = OnceLock::new();
+// Checked by capture so the device loop never takes the session lock
+static RECORDING: AtomicBool = AtomicBool::new(false);
+// Events written to the active session, kept by the writer thread
+static WRITTEN: AtomicU64 = AtomicU64::new(0);
+// Captures go through a channel to a writer thread so the device loop never waits on disk
+static WRITER: OnceLock<mpsc::Sender<SessionWrite>> = OnceLock::new();
+
+fn active() -> &'static Mutex<Option<ActiveSession>> {
+    ACTIVE.get_or_init(|| Mutex::new(None))
+}
+
+fn writer() -> &'static mpsc::Sender<SessionWrite> {
+    WRITER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel();
+        if let Err(e) = std::thread::Builder::new().name("snap-sessions".to_string()).spawn(move || write_loop(rx)) {
+            info!("Failed to start the session writer: {}", e);
+        }
+        tx
+    })
+}
+
+fn write_loop(rx: mpsc::Receiver<SessionWrite>) {
+    let mut current: Option<(String, LineWriter<File>)> = None;
+    for message in rx {
+        match message {
+            SessionWrite::Open(id, file) => {
+                WRITTEN.store(0, Ordering::Relaxed);
+                current = Some((id, LineWriter::new(file)));
+            }
+            SessionWrite::Event(row) => {
+                let Some((id, events)) = current.as_mut() else {
+                    continue;
+                };
+                let written = serde_json
+                    ::to_writer(&mut *events, &row)
+                    .map_err(std::io::Error::from)
+                    .and_then(|_| events.write_all(b"\n"));
+                match written {
+                    Ok(()) => {
+                        WRITTEN.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => info!("Failed to record event in session {}: {}", id, e),
+                }
+            }
+            SessionWrite::Flush(reply) => {
+                if let Some((_, events)) = current.as_mut() {
+                    let _ = events.flush();
+                }
+                let _ = reply.send(());
+            }
+            SessionWrite::Close(reply) => {
+                if let Some((_, mut events)) = current.take() {
+                    let _ = events.flush();
+                }
+                let _ = reply.send(());
+            }
+        }
+    }
+}
+
+// Wait until the writer has caught up, optionally closing the session file
+fn flush(close: bool) {
+    let (tx, rx) = mpsc::channel();
+    let message = if close { SessionWrite::Close(tx) } else { SessionWrite::Flush(tx) };
+    if writer().send(message).is_ok() {
+        let _ = rx.recv();
+    }
+}
+
+pub fn sessions_dir() -> PathBuf {
+    paths::data_dir().join("sessions")
+}
+
+fn meta_path(id: &str) -> PathBuf {
+    sessions_dir().join(format!("{}.json", id))
+}
+
+fn events_path(id: &str) -> PathBuf {
+    sessions_dir().join(format!("{}.jsonl", id))
+}
+
+fn write_meta(info: &SessionInfo) -> std::io::Result<()> {
+    fs::write(meta_path(&info.id), serde_json::to_vec_pretty(info)?)
+}
+
+// Ids are only ever generated here, but they also arrive in URLs
+fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+pub fn start(request: StartRecording) -> Result<SessionInfo, String> {
+    let name = request.name.trim().to_string();
+    if name.is_empty() {
+        return Err("a session needs a name".to_string());
+    }
+    let mut active = active().lock().map_err(|_| "recording is unavailable")?;
+    if let Some(current) = active.as_ref() {
+        return Err(format!("session '{}' ({}) is already recording", current.info.name, current.info.id));
+    }
+
+    fs::create_dir_all(sessions_dir()).map_err(|e| e.to_string())?;
+    let now = Utc::now();
+    let base = now.format("%Y%m%d-%H%M%S").to_string();
+    let mut id = base.clone();
+    let mut suffix = 1;
+    while meta_path(&id).exists() {
+        suffix += 1;
+        id = format!("{}-{}", base, suffix);
+    }
+
+    let info = SessionInfo {
+        id,
+        name,
+        operator: request.operator,
+        notes: request.notes,
+        status: SessionStatus::Recording,
+        started_at: now.to_rfc3339(),
+        stopped_at: None,
+        event_count: 0,
+    };
+    let events = OpenOptions::new()
+        .create_new(true)
+        .append(true)
+        .open(events_path(&info.id))
+        .map_err(|e| e.to_string())?;
+    write_meta(&info).map_err(|e| e.to_string())?;
+
+    writer()
+        .send(SessionWrite::Open(info.id.clone(), events))
+        .map_err(|_| "the session writer is not running".to_string())?;
+    RECORDING.store(true, Ordering::Relaxed);
+
+    info!("Started recording session '{}' ({})", info.name, info.id);
+    *active = Some(ActiveSession { info: info.clone() });
+    Ok(info)
+}
+
+// Waits for the writer thread to close the file, so it runs off the async workers
+pub async fn stop() -> Result<SessionInfo, String> {
+    tokio::task
+        ::spawn_blocking(finish).await
+        .unwrap_or_else(|e| Err(e.to_string()))
+}
+
+fn finish() -> Result<SessionInfo, String> {
+    let mut active = active().lock().map_err(|_| "recording is unavailable")?;
+    let Some(mut session) = active.take() else {
+        return Err("no session is recording".to_string());
+    };
+    RECORDING.store(false, Ordering::Relaxed);
+    flush(true);
+    session.info.event_count = WRITTEN.load(Ordering::Relaxed);
+    session.info.status = SessionStatus::Stopped;
+    session.info.stopped_at = Some(Utc::now().to_rfc3339());
+    write_meta(&session.info).map_err(|e| e.to_string())?;
+
+    info!(
+        "Stopped recording session '{}' ({}) with {} events",
+        session.info.name,
+        session.info.id,
+        session.info.event_count
+    );
+    Ok(session.info)
+}
+
+// Queue an event for the recording session, if there is one
+pub fn capture(event: &SnapDataEvent, device: &str) {
+    if !RECORDING.load(Ordering::Relaxed) {
+        return;
+    }
+    let _ = writer().send(SessionWrite::Event(SessionEvent {
+        seq: event.seq,
+        timestamp: event.timestamp.clone(),
+        device: device.to_string(),
+        mac: event.mac.clone(),
+        value: event.value,
+        pid: event.pid,
+    }));
+}
+
+pub fn active_id() -> Option<String> {
+    active().lock().ok()?.as_ref().map(|session| session.info.id.clone())
+}
+
+pub fn get(id: &str) -> Option<SessionInfo> {
+    if !valid_id(id) {
+        return None;
+    }
+    if let Ok(active) = active().lock() && let Some(session) = active.as_ref() && session.info.id == id {
+        let mut info = session.info.clone();
+        info.event_count = WRITTEN.load(Ordering::Relaxed);
+        return Some(info);
+    }
+    let mut info: SessionInfo = serde_json::from_slice(&fs::read(meta_path(id)).ok()?).ok()?;
+    // Still marked as recording on disk but not ours: the agent stopped mid-session
+    if info.status == SessionStatus::Recording {
+        info.status = SessionStatus::Interrupted;
+        info.event_count = File::open(events_path(id))
+            .map(|file| BufReader::new(file).lines().count() as u64)
+            .unwrap_or(0);
+    }
+    Some(info)
+}
+
+// All sessions, newest first
+pub fn list() -> Vec<SessionInfo> {
+    let Ok(entries) = fs::read_dir(sessions_dir()) else {
+        return Vec::new();
+    };
+    let mut sessions: Vec<SessionInfo> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.extension()? != "json" {
+                return None;
+            }
+            get(path.file_stem()?.to_str()?)
+        })
+        .collect();
+    sessions.sort_by(|a, b| b.started_at.cmp(&a.started_at));
+    sessions
+}
+
+// A session's events, read one line at a time
+fn read_events(id: &str) -> Result<impl Iterator<Item = SessionEvent>, String> {
+    let file = File::open(events_path(id)).map_err(|e| e.to_string())?;
+    // A crash can leave a torn last line; skip anything unreadable
+    Ok(
+        BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str(&line).ok())
+    )
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) { format!("\"{}\"", value.replace('"', "\"\"")) } else { value.to_string() }
+}
+
+fn write_csv(events: impl Iterator<Item = SessionEvent>, out: &mut impl Write) -> std::io::Result<()> {
+    out.write_all(b"seq,timestamp,device,mac,value,pid\n")?;
+    for event in events {
+        writeln!(
+            out,
+            "{},{},{},{},{},{}",
+            event.seq,
+            csv_field(&event.timestamp),
+            csv_field(&event.device),
+            csv_field(&event.mac),
+            event.value,
+            event.pid
+        )?;
+    }
+    out.flush()
+}
+
+fn write_jsonl(events: impl Iterator<Item = SessionEvent>, out: &mut impl Write) -> std::io::Result<()> {
+    for event in events {
+        serde_json::to_writer(&mut *out, &event)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+fn write_parquet(
+    mut events: impl Iterator<Item = SessionEvent>,
+    out: impl Write + Send
+) -> Result<(), parquet::errors::ParquetError> {
+    let schema = Arc::new(
+        parse_message_type(
+            "
+            message snap_event {
+                REQUIRED INT64 seq;
+                REQUIRED BYTE_ARRAY timestamp (UTF8);
+                REQUIRED BYTE_ARRAY device (UTF8);
+                REQUIRED BYTE_ARRAY mac (UTF8);
+                REQUIRED INT32 value;
+                REQUIRED INT32 pid;
+            }
+            "
+        )?
+    );
+    let properties = Arc::new(WriterProperties::builder().set_compression(Compression::SNAPPY).build());
+    let mut writer = SerializedFileWriter::new(out, schema, properties)?;
+    loop {
+        let chunk: Vec<SessionEvent> = events.by_ref().take(PARQUET_ROW_GROUP).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        write_row_group(&mut writer, &chunk)?;
+    }
+    writer.into_inner()?.flush()?;
+    Ok(())
+}
+
+fn write_row_group<W: Write + Send>(
+    writer: &mut SerializedFileWriter<W>,
+    events: &[SessionEvent]
+) -> Result<(), parquet::errors::ParquetError> {
+    let mut row_group = writer.next_row_group()?;
+    let text = |field: fn(&SessionEvent) -> &str| -> Vec<ByteArray> {
+        events
+            .iter()
+            .map(|event| ByteArray::from(field(event)))
+            .collect()
+    };
+    let mut column_index = 0;
+    while let Some(mut column) = row_group.next_column()? {
+        match column_index {
+            0 => {
+                let values: Vec<i64> = events
+                    .iter()
+                    .map(|event| event.seq as i64)
+                    .collect();
+                column.typed::<Int64Type>().write_batch(&values, None, None)?;
+            }
+            1 => {
+                column.typed::<ByteArrayType>().write_batch(&text(|event| &event.timestamp), None, None)?;
+            }
+            2 => {
+                column.typed::<ByteArrayType>().write_batch(&text(|event| &event.device), None, None)?;
+            }
+            3 => {
+                column.typed::<ByteArrayType>().write_batch(&text(|event| &event.mac), None, None)?;
+            }
+            4 => {
+                let values: Vec<i32> = events
+                    .iter()
+                    .map(|event| event.value as i32)
+                    .collect();
+                column.typed::<Int32Type>().write_batch(&values, None, None)?;
+            }
+            _ => {
+                let values: Vec<i32> = events
+                    .iter()
+                    .map(|event| event.pid as i32)
+                    .collect();
+                column.typed::<Int32Type>().write_batch(&values, None, None)?;
+            }
+        }
+        column.close()?;
+        column_index += 1;
+    }
+    row_group.close()?;
+    Ok(())
+}
+
+pub fn response(command: &str, result: Result<SessionInfo, String>) -> SessionResponse {
+    match result {
+        Ok(session) =>
+            SessionResponse {
+                success: true,
+                command: command.to_string(),
+                session: Some(session),
+                error: None,
+            },
+        Err(e) =>
+            SessionResponse {
+                success: false,
+                command: command.to_string(),
+                session: None,
+                error: Some(e),
+            },
+    }
+}
+
+pub fn list_response() -> SessionListResponse {
+    SessionListResponse {
+        success: true,
+        command: "list-recordings".to_string(),
+        sessions: list(),
+        error: None,
+    }
+}
+
+// Stream a session's events to `out` as csv, jsonl or parquet
+pub fn export(id: &str, format: &str, mut out: impl Write + Send) -> Result<(), String> {
+    if get(id).is_none() {
+        return Err(format!("unknown session {}", id));
+    }
+    if !["csv", "jsonl", "parquet"].contains(&format) {
+        return Err(format!("unsupported export format '{}' (use csv, jsonl or parquet)", format));
+    }
+    // Make sure everything captured so far is on disk
+    if active_id().as_deref() == Some(id) {
+        flush(false);
+    }
+    let events = read_events(id)?;
+    match format {
+        "csv" => write_csv(events, &mut out).map_err(|e| e.to_string()),
+        "jsonl" => write_jsonl(events, &mut out).map_err(|e| e.to_string()),
+        _ => write_parquet(events, out).map_err(|e| e.to_string()),
+    }
+}
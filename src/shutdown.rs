@@ -0,0 +1,73 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::info;
+
+// Upper bound for stopping collection, notifying clients and draining connections
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+static SHUTDOWN: OnceLock<CancellationToken> = OnceLock::new();
+static DEVICE_TASKS: OnceLock<TaskTracker> = OnceLock::new();
+
+// Cancelled once the agent starts shutting down; every long-running task
+// holds a clone (or a child) of this token
+pub fn token() -> CancellationToken {
+    SHUTDOWN.get_or_init(CancellationToken::new).clone()
+}
+
+pub fn is_shutting_down() -> bool {
+    token().is_cancelled()
+}
+
+pub fn trigger(reason: &str) {
+    let token = token();
+    if !token.is_cancelled() {
+        info!("Shutdown requested: {}", reason);
+        token.cancel();
+    }
+}
+
+// Tasks that own a serial port or USB handle, so shutdown can wait for them
+// to release the device
+pub fn device_tasks() -> &'static TaskTracker {
+    DEVICE_TASKS.get_or_init(TaskTracker::new)
+}
+
+// Sleep that returns early when shutdown starts
+pub async fn sleep(duration: Duration) {
+    let token = token();
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => {}
+        _ = token.cancelled() => {}
+    }
+}
+
+#[cfg(unix)]
+async fn next_signal() -> &'static str {
+    use tokio::signal::unix::{ signal, SignalKind };
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Failed to install SIGINT handler");
+    tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    }
+}
+
+#[cfg(not(unix))]
+async fn next_signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "Ctrl-C"
+}
+
+// Trigger shutdown on the first SIGTERM/SIGINT; a second one exits immediately
+pub fn listen_for_signals() {
+    tokio::spawn(async {
+        let signal = next_signal().await;
+        trigger(signal);
+        let signal = next_signal().await;
+        info!("Received {} during shutdown, exiting immediately", signal);
+        std::process::exit(130);
+    });
+}
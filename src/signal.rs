@@ -0,0 +1,213 @@
+use std::collections::{ HashMap, VecDeque };
+use std::sync::{ Mutex, OnceLock };
+use tracing::info;
+use crate::{ config::{ self, ProcessingRule }, filter, models::* };
+
+// Processing state of one peripheral
+#[derive(Default)]
+struct Channel {
+    window: VecDeque<f64>,
+    low_pass: Option<f64>,
+    last_value: Option<u16>,
+    // Value at the last `snappy-change`
+    reported: Option<f64>,
+    // Highest value and when it was seen, and when the value crossed the threshold
+    peak: Option<(f64, String, String)>,
+}
+
+// What became of one event
+pub struct Processed {
+    // False when debouncing keeps it out of the live stream
+    pub emit: bool,
+    pub change: Option<SnapChangeEvent>,
+    pub peak: Option<SnapPeakEvent>,
+}
+
+static RULES: OnceLock<(Option<ProcessingRule>, HashMap<String, ProcessingRule>)> = OnceLock::new();
+static CHANNELS: OnceLock<Mutex<HashMap<String, Channel>>> = OnceLock::new();
+
+fn rule_for(mac: &str) -> Option<&'static ProcessingRule> {
+    let (default, by_mac) = RULES.get_or_init(|| {
+        let processing = &config::get().processing;
+        let mut by_mac = HashMap::new();
+        for (key, rule) in &processing.mac {
+            match filter::parse_mac(key) {
+                Some(mac) => {
+                    by_mac.insert(mac, rule.clone());
+                }
+                None => info!("Ignoring processing rule for invalid MAC {:?}", key),
+            }
+        }
+        (processing.default.clone(), by_mac)
+    });
+    by_mac.get(mac).or(default.as_ref())
+}
+
+// Smooth the event's value and run change/peak detection on it. Works on the
+// calibrated value when there is one.
+pub fn process(event: &mut SnapDataEvent, device: &str) -> Processed {
+    let Some(rule) = rule_for(&event.mac) else {
+        return Processed { emit: true, change: None, peak: None };
+    };
+    let mut channels = CHANNELS.get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    let channel = channels.entry(event.mac.clone()).or_default();
+    step(rule, channel, event, device)
+}
+
+fn step(rule: &ProcessingRule, channel: &mut Channel, event: &mut SnapDataEvent, device: &str) -> Processed {
+    let emit = !(rule.debounce && channel.last_value == Some(event.value));
+    channel.last_value = Some(event.value);
+
+    let mut value = event.calibrated.unwrap_or(event.value as f64);
+    if rule.moving_average > 1 {
+        if channel.window.len() >= rule.moving_average {
+            channel.window.pop_front();
+        }
+        channel.window.push_back(value);
+        value = channel.window.iter().sum::<f64>() / (channel.window.len() as f64);
+    }
+    if let Some(alpha) = rule.low_pass_alpha {
+        let alpha = alpha.clamp(0.0, 1.0);
+        value = channel.low_pass.map_or(value, |previous| previous + alpha * (value - previous));
+        channel.low_pass = Some(value);
+    }
+    if rule.moving_average > 1 || rule.low_pass_alpha.is_some() {
+        event.filtered = Some(value);
+    }
+
+    let mut change = None;
+    if let Some(threshold) = rule.change_threshold {
+        match channel.reported {
+            Some(previous) if (value - previous).abs() >= threshold => {
+                change = Some(SnapChangeEvent {
+                    mac: event.mac.clone(),
+                    pid: event.pid,
+                    device: device.to_string(),
+                    previous,
+                    value,
+                    timestamp: event.timestamp.clone(),
+                });
+                channel.reported = Some(value);
+            }
+            Some(_) => {}
+            None => {
+                channel.reported = Some(value);
+            }
+        }
+    }
+
+    let mut peak = None;
+    if let Some(threshold) = rule.peak_threshold {
+        match channel.peak.take() {
+            None if value >= threshold => {
+                channel.peak = Some((value, event.timestamp.clone(), event.timestamp.clone()));
+            }
+            None => {}
+            // A peak ends once the value falls below the threshold minus the hysteresis
+            Some((highest, peak_at, started_at)) if value < threshold - rule.peak_hysteresis => {
+                peak = Some(SnapPeakEvent {
+                    mac: event.mac.clone(),
+                    pid: event.pid,
+                    device: device.to_string(),
+                    peak: highest,
+                    peak_at,
+                    started_at,
+                    ended_at: event.timestamp.clone(),
+                });
+            }
+            Some((highest, _, started_at)) if value > highest => {
+                channel.peak = Some((value, event.timestamp.clone(), started_at));
+            }
+            ongoing => {
+                channel.peak = ongoing;
+            }
+        }
+    }
+
+    Processed { emit, change, peak }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: &str = "aa:bb:cc:dd:ee:ff";
+
+    // Feed `values` through one channel, one second apart
+    fn run(rule: &ProcessingRule, values: &[u16]) -> Vec<(SnapDataEvent, Processed)> {
+        let mut channel = Channel::default();
+        values
+            .iter()
+            .enumerate()
+            .map(|(second, &value)| {
+                let mut event = SnapDataEvent::raw(MAC, value, &format!("2025-01-01T00:00:{:02}Z", second));
+                let processed = step(rule, &mut channel, &mut event, "/dev/ttyACM0");
+                (event, processed)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn debounce_drops_repeated_raw_values() {
+        let rule = ProcessingRule { debounce: true, ..Default::default() };
+        let emitted: Vec<bool> = run(&rule, &[1, 1, 2, 2, 1]).iter().map(|(_, processed)| processed.emit).collect();
+        assert_eq!(emitted, [true, false, true, false, true]);
+    }
+
+    #[test]
+    fn moving_average_covers_the_window() {
+        let rule = ProcessingRule { moving_average: 3, ..Default::default() };
+        let filtered: Vec<Option<f64>> = run(&rule, &[3, 6, 9, 12]).iter().map(|(event, _)| event.filtered).collect();
+        assert_eq!(filtered, [Some(3.0), Some(4.5), Some(6.0), Some(9.0)]);
+    }
+
+    #[test]
+    fn low_pass_moves_towards_the_value() {
+        let rule = ProcessingRule { low_pass_alpha: Some(0.5), ..Default::default() };
+        let filtered: Vec<Option<f64>> = run(&rule, &[0, 100, 100]).iter().map(|(event, _)| event.filtered).collect();
+        assert_eq!(filtered, [Some(0.0), Some(50.0), Some(75.0)]);
+    }
+
+    #[test]
+    fn filtered_is_unset_without_smoothing() {
+        let rule = ProcessingRule { debounce: true, ..Default::default() };
+        assert_eq!(run(&rule, &[5])[0].0.filtered, None);
+    }
+
+    #[test]
+    fn smoothing_uses_the_calibrated_value() {
+        let rule = ProcessingRule { moving_average: 2, ..Default::default() };
+        let mut channel = Channel::default();
+        let mut event = SnapDataEvent::raw(MAC, 10, "");
+        event.calibrated = Some(1.5);
+        step(&rule, &mut channel, &mut event, "");
+        assert_eq!(event.filtered, Some(1.5));
+    }
+
+    #[test]
+    fn change_is_reported_against_the_last_reported_value() {
+        let rule = ProcessingRule { change_threshold: Some(10.0), ..Default::default() };
+        let changes: Vec<(f64, f64)> = run(&rule, &[100, 105, 109, 110, 115, 99])
+            .into_iter()
+            .filter_map(|(_, processed)| processed.change)
+            .map(|change| (change.previous, change.value))
+            .collect();
+        assert_eq!(changes, [(100.0, 110.0), (110.0, 99.0)]);
+    }
+
+    #[test]
+    fn peak_ends_below_the_hysteresis() {
+        let rule = ProcessingRule { peak_threshold: Some(50.0), peak_hysteresis: 5.0, ..Default::default() };
+        let results = run(&rule, &[10, 60, 80, 47, 70, 44, 10]);
+        let peaks: Vec<&SnapPeakEvent> = results.iter().filter_map(|(_, processed)| processed.peak.as_ref()).collect();
+        assert_eq!(peaks.len(), 1);
+        let peak = peaks[0];
+        assert_eq!(peak.peak, 80.0);
+        assert_eq!(peak.started_at, "2025-01-01T00:00:01Z");
+        assert_eq!(peak.peak_at, "2025-01-01T00:00:02Z");
+        assert_eq!(peak.ended_at, "2025-01-01T00:00:05Z");
+        assert_eq!(peak.device, "/dev/ttyACM0");
+    }
+}
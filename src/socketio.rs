@@ -1,51 +1,536 @@
 use serde_json::Value;
-use socketioxide::{ extract::{ AckSender, Data, SocketRef } };
+use socketioxide::{ extract::{ AckSender, Data, SocketRef, TryData }, socket::Sid, SocketIo };
 use tracing::info;
-use std::sync::{ Arc, Mutex };
-use std::sync::atomic::{ AtomicBool, Ordering };
+use std::collections::{ HashMap, HashSet };
+use std::sync::{ Mutex, MutexGuard, OnceLock };
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use std::time::Duration;
 use chrono::Utc;
-use crate::{ models::*, serial };
+use crate::{
+    aggregate,
+    config,
+    delivery,
+    encoding,
+    filter,
+    models::*,
+    peripherals,
+    protocol,
+    rules,
+    serial,
+    sessions,
+    shutdown,
+    signal,
+    store,
+    stream,
+    transform,
+};
 
 // Global state for controlling data collection
 static SNAPPY_COLLECTING: AtomicBool = AtomicBool::new(false);
+// Bumped on every start, so device loops left over from an earlier collection exit
+static COLLECTION: AtomicU64 = AtomicU64::new(0);
 
-// Use a thread-safe approach instead of unsafe
-static SNAPPY_SOCKET: std::sync::OnceLock<Arc<Mutex<Option<SocketRef>>>> = std::sync::OnceLock::new();
+// How long start-snappy waits for the device to open
+const PORT_OPEN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A client receiving the live stream, optionally only from some peripherals
+struct Subscriber {
+    socket: SocketRef,
+    macs: Option<HashSet<String>>,
+}
+
+impl Subscriber {
+    fn wants(&self, mac: &str) -> bool {
+        self.macs.as_ref().is_none_or(|macs| macs.contains(mac))
+    }
+}
+
+// Clients that asked for data with start-snappy (or resumed while it runs)
+static SUBSCRIBERS: OnceLock<Mutex<HashMap<Sid, Subscriber>>> = OnceLock::new();
+
+fn subscribers() -> MutexGuard<'static, HashMap<Sid, Subscriber>> {
+    SUBSCRIBERS.get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+// `macs` from a request: None when absent, Some(None) for null (every peripheral)
+fn macs_of(data: &Value) -> Result<Option<Option<HashSet<String>>>, String> {
+    match data.get("macs") {
+        None => Ok(None),
+        Some(Value::Null) => Ok(Some(None)),
+        Some(macs) => {
+            let macs: Vec<String> = serde_json
+                ::from_value(macs.clone())
+                .map_err(|_| "macs must be a list of MAC addresses".to_string())?;
+            Ok(Some(Some(filter::parse_macs(&macs)?)))
+        }
+    }
+}
+
+static SOCKET_IO: OnceLock<SocketIo> = OnceLock::new();
+
+// Keep a handle to the server so shutdown can reach every client
+pub fn set_io(io: SocketIo) {
+    let _ = SOCKET_IO.set(io);
+}
+
+// Send an event to every connected client, on every namespace, without waiting for delivery
+pub fn broadcast<T: serde::Serialize + Send + Sync + 'static>(event: impl Into<String>, data: T) {
+    if let Some(io) = SOCKET_IO.get() {
+        let io = io.clone();
+        let event = event.into();
+        tokio::spawn(async move {
+            for ns in ["/", protocol::V2_NAMESPACE] {
+                if let Some(operators) = io.of(ns) {
+                    let _ = operators.emit(event.clone(), &data).await;
+                }
+            }
+        });
+    }
+}
+
+// Tell every client about a device failure
+pub fn report_error(code: ErrorCode, message: impl Into<String>, device: Option<&str>) {
+    let event = SnappyErrorEvent {
+        code,
+        message: message.into(),
+        device: device.map(str::to_string),
+        timestamp: Utc::now().to_rfc3339(),
+    };
+    broadcast("snappy-error", event);
+}
+
+// Several clients (and the rule engine) can need the stream; only the first
+// starts the collection task
+pub fn start_collecting() {
+    if !SNAPPY_COLLECTING.swap(true, Ordering::Relaxed) {
+        info!("Starting snappy data collection for all supported devices");
+        let generation = COLLECTION.fetch_add(1, Ordering::Relaxed) + 1;
+        serial::set_port_state(serial::PortState::Opening, None);
+        shutdown::device_tasks().spawn(serial::start_snappy(generation));
+    }
+}
+
+// Stop collection when nothing needs the stream any more; true if it stopped
+fn release_collection() -> bool {
+    if !subscribers().is_empty() || !aggregate::is_empty() || rules::any() {
+        return false;
+    }
+    if SNAPPY_COLLECTING.swap(false, Ordering::Relaxed) {
+        info!("Stopping snappy data collection");
+    }
+    true
+}
 
 // Function to check if snappy is collecting data
 pub fn is_snappy_collecting() -> bool {
-    SNAPPY_COLLECTING.load(Ordering::Relaxed)
+    SNAPPY_COLLECTING.load(Ordering::Relaxed) && !shutdown::is_shutting_down()
+}
+
+// Whether the collection a device loop was started for is still the current one
+pub fn is_collection_current(generation: u64) -> bool {
+    is_snappy_collecting() && COLLECTION.load(Ordering::Relaxed) == generation
+}
+
+// Stop collection, tell every client the agent is going away and close their connections
+pub async fn shutdown() {
+    SNAPPY_COLLECTING.store(false, Ordering::Relaxed);
+    subscribers().clear();
+
+    if let Some(io) = SOCKET_IO.get() {
+        let event_response = EventResponse {
+            event: "agent-shutdown".to_string(),
+            status: "stopping".to_string(),
+        };
+        let _ = io.emit("agent-shutdown", &event_response).await;
+        if let Some(operators) = io.of(protocol::V2_NAMESPACE) {
+            let _ = operators.emit("agent-shutdown", &ShutdownEvent { reason: "stopping".to_string() }).await;
+        }
+        io.close().await;
+    }
 }
 
 // Enhanced function to emit snap data with PID information
-pub fn emit_snap_data(mac: String, value: u16, pid: u16) {
-    let socket_ref = SNAPPY_SOCKET.get_or_init(|| Arc::new(Mutex::new(None)));
-    if let Ok(socket_guard) = socket_ref.lock() {
-        if let Some(ref socket) = *socket_guard {
-            let timestamp = Utc::now().to_rfc3339();
-
-            let snap_data = SnapDataEvent {
-                mac,
-                value,
-                timestamp,
-                pid, // Include PID in the data
-            };
-
-            let _ = socket.emit("snappy-data", &snap_data);
+pub fn emit_snap_data(mac: String, value: u16, pid: u16, device: &str, extra: Option<serde_json::Map<String, Value>>) {
+    let timestamp = Utc::now().to_rfc3339();
+
+    // Sequencing under the subscribers lock keeps a concurrent resume from
+    // missing or repeating an event
+    let subscribers = subscribers();
+    let mut snap_data = SnapDataEvent {
+        mac,
+        value,
+        timestamp,
+        pid, // Include PID in the data
+        seq: 0,
+        run: 0,
+        calibrated: None,
+        unit: None,
+        filtered: None,
+        extra,
+    };
+    transform::apply(&mut snap_data);
+    let processed = signal::process(&mut snap_data, device);
+    rules::evaluate(&snap_data, device);
+    let mac = snap_data.mac.clone();
+    let wanted = || subscribers.values().filter(|subscriber| subscriber.wants(&mac));
+
+    // Debounced repeats are still stored, recorded and aggregated; they only
+    // stay out of the live stream and its replay buffer
+    let snap_data = stream::sequence(snap_data, processed.emit);
+    // Stored even when no client is listening, so a reloaded page can catch up
+    store::record(&snap_data, device);
+    sessions::capture(&snap_data, device);
+    stream::remember(&snap_data, device);
+    aggregate::observe(&snap_data);
+    // Changes and peaks share each client's batching and rate limit
+    for subscriber in wanted() {
+        if processed.emit {
+            delivery::send(&subscriber.socket, delivery::Outgoing::Data(snap_data.clone()));
         }
+        if let Some(change) = &processed.change {
+            delivery::send(&subscriber.socket, delivery::Outgoing::Change(change.clone()));
+        }
+        if let Some(peak) = &processed.peak {
+            delivery::send(&subscriber.socket, delivery::Outgoing::Peak(peak.clone()));
+        }
+    }
+}
+
+// A script's event, for the subscribed clients that receive `mac`
+pub fn emit_script_event(event: String, mac: &str, data: Value) {
+    if !filter::allows(mac) {
+        return;
+    }
+    let subscribers = subscribers();
+    for subscriber in subscribers.values().filter(|subscriber| subscriber.wants(mac)) {
+        delivery::send(&subscriber.socket, delivery::Outgoing::Script(event.clone(), data.clone()));
     }
 }
 
-pub async fn on_connect(socket: SocketRef, Data(_data): Data<Value>) {
+// Latest values from the peripherals `macs` (all when None) that the MAC filter
+// still allows
+pub fn snapshot(macs: Option<&HashSet<String>>) -> SnapshotResponse {
+    SnapshotResponse {
+        success: true,
+        command: "snapshot".to_string(),
+        values: stream::snapshot(|mac| filter::allows(mac) && macs.is_none_or(|macs| macs.contains(mac))),
+        error: None,
+    }
+}
+
+fn resume_error(error: String) -> ResumeResponse {
+    ResumeResponse {
+        success: false,
+        command: "resume".to_string(),
+        replayed: 0,
+        last_seq: stream::last_seq(),
+        run: stream::run_id(),
+        gap: false,
+        error: Some(error),
+    }
+}
+
+// Send `socket` the events after `last_seq` and, while collection is running,
+// subscribe it to the live stream. `data` is { last_seq, run?, macs? }.
+fn resume(socket: &SocketRef, data: &Value) -> ResumeResponse {
+    let Some(last_seq) = data.get("last_seq").and_then(Value::as_u64) else {
+        return resume_error("resume expects { last_seq: <number>, run?: <number>, macs?: [...] }".to_string());
+    };
+    let run = match data.get("run") {
+        None | Some(Value::Null) => None,
+        Some(run) =>
+            match run.as_u64() {
+                Some(run) => Some(run),
+                None => {
+                    return resume_error("run must be the number from an earlier event".to_string());
+                }
+            }
+    };
+    let macs = match macs_of(data) {
+        Ok(macs) => macs,
+        Err(e) => {
+            return resume_error(e);
+        }
+    };
+
+    let mut subscribers = subscribers();
+    // Without an explicit filter keep the one the client subscribed with
+    let macs = macs.unwrap_or_else(|| subscribers.get(&socket.id).and_then(|subscriber| subscriber.macs.clone()));
+    let subscriber = Subscriber { socket: socket.clone(), macs };
+
+    let (missed, gap) = stream::since(last_seq, run);
+    let mut replayed = 0;
+    for event in missed.iter().filter(|event| subscriber.wants(&event.mac)) {
+        let _ = encoding::emit_data(socket, event);
+        replayed += 1;
+    }
+    if is_snappy_collecting() {
+        subscribers.insert(socket.id, subscriber);
+    }
+    info!("Resumed client {} after seq {}: replayed {} events (gap: {})", socket.id, last_seq, replayed, gap);
+
+    ResumeResponse {
+        success: true,
+        command: "resume".to_string(),
+        replayed,
+        last_seq: stream::last_seq(),
+        run: stream::run_id(),
+        gap,
+        error: None,
+    }
+}
+
+// Handlers shared by every namespace
+fn register(socket: &SocketRef, data: &Value) {
     info!(ns = socket.ns(), ?socket.id, "Socket.IO connected");
+    protocol::from_auth(socket.id, socket.ns(), data);
     check_port_connection(socket.clone());
+
+    // Binary snap data is negotiated with { encoding: "binary" } in the auth data
+    if let Some(encoding) = data.get("encoding").and_then(|encoding| serde_json::from_value::<Encoding>(encoding.clone()).ok()) {
+        encoding::set(socket.id, encoding);
+    }
+
+    // A reconnecting client can pass { last_seq, run?, macs? } as connection auth data
+    if data.get("last_seq").is_some() {
+        resume(socket, data);
+    }
+
+    // ...or ask explicitly, e.g. after its own reconnect logic
+    socket.on("resume", |socket: SocketRef, Data(data): Data<Value>, ack: AckSender| {
+        let _ = ack.send(&resume(&socket, &data));
+    });
+
+    // A client that goes away stops receiving data, but collection keeps
+    // running (and buffering) so it can resume
+    socket.on_disconnect(|socket: SocketRef| {
+        subscribers().remove(&socket.id);
+        aggregate::unsubscribe(socket.id);
+        delivery::remove(socket.id);
+        encoding::remove(socket.id);
+        protocol::remove(socket.id);
+    });
+
+    // Protocol negotiation and what this agent supports
+    socket.on("hello", |socket: SocketRef, Data(data): Data<Value>, ack: AckSender| {
+        let request = serde_json
+            ::from_value::<HelloRequest>(data)
+            .map_err(|e| format!("hello expects {{ protocol: <number>, client?: <name> }}: {}", e));
+        if let Ok(request) = &request {
+            info!(
+                "Client {} ({}) says hello with protocol {}",
+                socket.id,
+                request.client.as_deref().unwrap_or("unnamed"),
+                request.protocol
+            );
+        }
+        let _ = ack.send(&protocol::hello(socket.id, socket.ns(), request));
+    });
+
+    // Switch between "json" and "binary" after connecting
+    socket.on("set-encoding", |socket: SocketRef, Data(data): Data<Value>, ack: AckSender| {
+        let response = match data.get("encoding").map(|encoding| serde_json::from_value::<Encoding>(encoding.clone())) {
+            Some(Ok(encoding)) => {
+                encoding::set(socket.id, encoding);
+                EncodingResponse {
+                    success: true,
+                    command: "set-encoding".to_string(),
+                    encoding: Some(encoding),
+                    record_size: encoding::RECORD_SIZE,
+                    error: None,
+                }
+            }
+            _ =>
+                EncodingResponse {
+                    success: false,
+                    command: "set-encoding".to_string(),
+                    encoding: None,
+                    record_size: encoding::RECORD_SIZE,
+                    error: Some("set-encoding expects { encoding: \"json\" | \"binary\" }".to_string()),
+                },
+        };
+        let _ = ack.send(&response);
+    });
+
+    // Batching, rate limit and queue size for this client's live events
+    socket.on("set-delivery", |socket: SocketRef, Data(data): Data<Value>, ack: AckSender| {
+        let result = serde_json
+            ::from_value::<DeliveryOptions>(data)
+            .map_err(|e| e.to_string())
+            .and_then(|options| delivery::configure(&socket, options));
+        let _ = ack.send(&delivery::response("set-delivery", socket.id, result));
+    });
+
+    socket.on("delivery-stats", |socket: SocketRef, ack: AckSender| {
+        let _ = ack.send(&delivery::response("delivery-stats", socket.id, Ok(())));
+    });
+
+    // Aggregated or resampled stream alongside (or instead of) the raw one
+    socket.on("subscribe-aggregate", |socket: SocketRef, Data(data): Data<Value>, ack: AckSender| {
+        let result = serde_json
+            ::from_value::<AggregateRequest>(data)
+            .map_err(|e| e.to_string())
+            .and_then(|request| aggregate::subscribe(&socket, request))
+            .map(Some);
+        if result.is_ok() {
+            start_collecting();
+        }
+        let _ = ack.send(&aggregate::response("subscribe-aggregate", result));
+    });
+
+    socket.on("unsubscribe-aggregate", |socket: SocketRef, ack: AckSender| {
+        let result = if aggregate::unsubscribe(socket.id) {
+            release_collection();
+            Ok(None)
+        } else {
+            Err("no aggregated stream to unsubscribe from".to_string())
+        };
+        let _ = ack.send(&aggregate::response("unsubscribe-aggregate", result));
+    });
+
+    // Limit this client's live stream to some peripherals; null for all of them
+    socket.on("set-subscription", |socket: SocketRef, Data(data): Data<Value>, ack: AckSender| {
+        let result = match macs_of(&data) {
+            Ok(Some(macs)) =>
+                match subscribers().get_mut(&socket.id) {
+                    Some(subscriber) => {
+                        subscriber.macs = macs;
+                        Ok(subscriber.macs.as_ref().map(sorted_macs))
+                    }
+                    None => Err("not subscribed; call start-snappy first".to_string()),
+                }
+            Ok(None) => Err("set-subscription expects { macs: [...] | null }".to_string()),
+            Err(e) => Err(e),
+        };
+        let _ = ack.send(&subscription_response(result));
+    });
+
+    // Optional data { macs: [...] }; defaults to this client's subscription
+    socket.on("snapshot", |socket: SocketRef, TryData(data): TryData<Value>, ack: AckSender| {
+        let response = match macs_of(&data.unwrap_or(Value::Null)) {
+            Ok(Some(macs)) => snapshot(macs.as_ref()),
+            Ok(None) => snapshot(subscribers().get(&socket.id).and_then(|subscriber| subscriber.macs.as_ref())),
+            Err(e) =>
+                SnapshotResponse {
+                    success: false,
+                    command: "snapshot".to_string(),
+                    values: Vec::new(),
+                    error: Some(e),
+                },
+        };
+        let _ = ack.send(&response);
+    });
+
+    socket.on("get-mac-filter", |ack: AckSender| {
+        let _ = ack.send(&filter::response("get-mac-filter", Ok(filter::current())));
+    });
+
+    socket.on("set-mac-filter", |Data(data): Data<Value>, ack: AckSender| {
+        let result = serde_json
+            ::from_value::<MacFilterUpdate>(data)
+            .map_err(|e| format!("set-mac-filter expects {{ allow?, deny? }}: {}", e))
+            .and_then(filter::update);
+        let _ = ack.send(&filter::response("set-mac-filter", result));
+    });
     
+    // Stored snap data filtered by time range, device, MAC and PID
+    socket.on("history", async |Data(query): Data<Value>, ack: AckSender| {
+        let response = match query {
+            Value::Null => store::history(HistoryQuery::default()).await,
+            query =>
+                match serde_json::from_value::<HistoryQuery>(query) {
+                    Ok(query) => store::history(query).await,
+                    Err(e) =>
+                        HistoryResponse {
+                            success: false,
+                            command: "history".to_string(),
+                            records: Vec::new(),
+                            truncated: false,
+                            error: Some(format!("invalid history query: {}", e)),
+                        },
+                }
+        };
+        let _ = ack.send(&response);
+    });
+
+    socket.on("start-recording", |Data(data): Data<Value>, ack: AckSender| {
+        let result = serde_json
+            ::from_value::<StartRecording>(data)
+            .map_err(|e| format!("start-recording expects {{ name, operator?, notes? }}: {}", e))
+            .and_then(sessions::start);
+        let _ = ack.send(&sessions::response("start-recording", result));
+    });
+
+    socket.on("stop-recording", async |ack: AckSender| {
+        let _ = ack.send(&sessions::response("stop-recording", sessions::stop().await));
+    });
+
+    socket.on("list-recordings", |ack: AckSender| {
+        let _ = ack.send(&sessions::list_response());
+    });
+
+    socket.on("peripherals", |ack: AckSender| {
+        let _ = ack.send(&peripherals::response(Ok(peripherals::list())));
+    });
+
+    socket.on("set-peripheral-alias", |Data(data): Data<Value>, ack: AckSender| {
+        let result = serde_json
+            ::from_value::<PeripheralAlias>(data)
+            .map_err(|e| format!("set-peripheral-alias expects {{ mac, alias }}: {}", e))
+            .and_then(|request| peripherals::set_alias(&request.mac, request.alias))
+            .map(|peripheral| vec![peripheral]);
+        let mut response = peripherals::response(result);
+        response.command = "set-peripheral-alias".to_string();
+        let _ = ack.send(&response);
+    });
+}
+
+// Add the client to the live stream, sending it a snapshot first.
+// Optional data { macs: [...] } limits the stream to those peripherals.
+fn subscribe(socket: &SocketRef, data: Option<Value>) -> Result<(), String> {
+    let macs = macs_of(&data.unwrap_or(Value::Null))?;
+    {
+        let mut subscribers = subscribers();
+        let macs = macs.unwrap_or_else(|| subscribers.get(&socket.id).and_then(|subscriber| subscriber.macs.clone()));
+        // Sent under the lock so no live event can overtake the snapshot
+        let _ = socket.emit("snappy-snapshot", &snapshot(macs.as_ref()));
+        subscribers.insert(socket.id, Subscriber { socket: socket.clone(), macs });
+    }
+    start_collecting();
+    Ok(())
+}
+
+// Subscribe, then wait for the device; a failed device keeps the subscription
+// so data flows once it is plugged in or freed
+async fn start(socket: &SocketRef, data: Option<Value>) -> Result<(), (ErrorCode, String)> {
+    subscribe(socket, data).map_err(|e| (ErrorCode::InvalidRequest, e))?;
+    serial::wait_for_port(PORT_OPEN_TIMEOUT).await
+}
+
+// Remove the client from the live stream; returns whether collection stopped
+// and how many clients are still subscribed
+fn unsubscribe(sid: Sid) -> (bool, usize) {
+    let remaining = {
+        let mut subscribers = subscribers();
+        subscribers.remove(&sid);
+        subscribers.len()
+    };
+    (release_collection(), remaining)
+}
+
+// Legacy namespace `/`: replies carry their details in a message string
+pub async fn on_connect(socket: SocketRef, Data(data): Data<Value>) {
+    register(&socket, &data);
+
     socket.on("version", |ack: AckSender| {
         let version = env!("CARGO_PKG_VERSION");
         let serial_response = SerialResponse {
             success: true,
             message: version.to_string(),
             command: "version".to_string(),
+            code: None,
             error: None,
         };
         ack.send(&serial_response).ok();
@@ -67,71 +552,186 @@ pub async fn on_connect(socket: SocketRef, Data(_data): Data<Value>) {
             success: true,
             message: device_info,
             command: "device-info".to_string(),
+            code: None,
             error: None,
         };
         ack.send(&serial_response).ok();
     });
-    
-    let socket_for_start = socket.clone();
-    socket.on("start-snappy", move |ack: AckSender| {
-        info!("Starting snappy data collection for all supported devices");
-        SNAPPY_COLLECTING.store(true, Ordering::Relaxed);
-
-        // Store socket reference for data emission
-        let socket_ref = SNAPPY_SOCKET.get_or_init(|| Arc::new(Mutex::new(None)));
-        if let Ok(mut socket_guard) = socket_ref.lock() {
-            *socket_guard = Some(socket_for_start.clone());
-        }
-
-        // Start the data collection task
-        let socket_ref = socket_for_start.clone();
-        tokio::spawn(async move {
-            serial::start_snappy_with_socket(socket_ref).await;
-        });
-
-        let serial_response = SerialResponse {
-            success: true,
-            message: format!("Snappy data collection started for PIDs: {:?}", 
-                           PIDS.iter().map(|&p| format!("0x{:04x}", p)).collect::<Vec<_>>()),
-            command: "start-snappy".to_string(),
-            error: None,
+    // Acknowledged once the device is open, or with the reason it is not
+    socket.on("start-snappy", async |socket: SocketRef, TryData(data): TryData<Value>, ack: AckSender| {
+        let serial_response = match start(&socket, data.ok()).await {
+            Ok(()) =>
+                SerialResponse {
+                    success: true,
+                    message: format!("Snappy data collection started for PIDs: {:?}", 
+                                   PIDS.iter().map(|&p| format!("0x{:04x}", p)).collect::<Vec<_>>()),
+                    command: "start-snappy".to_string(),
+                    code: None,
+                    error: None,
+                },
+            Err((ErrorCode::InvalidRequest, e)) =>
+                SerialResponse {
+                    success: false,
+                    message: "Invalid subscription".to_string(),
+                    command: "start-snappy".to_string(),
+                    code: Some(ErrorCode::InvalidRequest),
+                    error: Some(e),
+                },
+            Err((code, e)) =>
+                SerialResponse {
+                    success: false,
+                    message: "Subscribed; data will flow once the device is open".to_string(),
+                    command: "start-snappy".to_string(),
+                    code: Some(code),
+                    error: Some(e),
+                },
         };
         let _ = ack.send(&serial_response);
     });
 
-    socket.on("stop-snappy", move |ack: AckSender| {
-        info!("Stopping snappy data collection");
-        SNAPPY_COLLECTING.store(false, Ordering::Relaxed);
-
-        // Clear socket reference
-        let socket_ref = SNAPPY_SOCKET.get_or_init(|| Arc::new(Mutex::new(None)));
-        if let Ok(mut socket_guard) = socket_ref.lock() {
-            *socket_guard = None;
-        }
+    // Unsubscribe; collection stops once no client is subscribed (unless rules need it)
+    socket.on("stop-snappy", |socket: SocketRef, ack: AckSender| {
+        let message = match unsubscribe(socket.id) {
+            (true, _) => "Snappy data collection stopped for all devices".to_string(),
+            (false, remaining) if remaining > 0 =>
+                format!("Unsubscribed; collection continues for {} other client(s)", remaining),
+            (false, _) => "Unsubscribed; collection continues for aggregated streams or configured rules".to_string(),
+        };
 
         let serial_response = SerialResponse {
             success: true,
-            message: "Snappy data collection stopped for all devices".to_string(),
+            message,
             command: "stop-snappy".to_string(),
+            code: None,
             error: None,
         };
         let _ = ack.send(&serial_response);
     });
 }
 
+fn collection_response(command: &str, sid: Sid, result: Result<(), (ErrorCode, String)>) -> CollectionResponse {
+    let subscribers = subscribers();
+    let subscriber = subscribers.get(&sid);
+    let (code, error) = match result {
+        Ok(()) => (None, None),
+        Err((code, e)) => (Some(code), Some(e)),
+    };
+    CollectionResponse {
+        success: error.is_none(),
+        command: command.to_string(),
+        subscribed: subscriber.is_some(),
+        macs: subscriber.and_then(|subscriber| subscriber.macs.as_ref()).map(sorted_macs),
+        collecting: is_snappy_collecting(),
+        subscribers: subscribers.len(),
+        code,
+        error,
+    }
+}
+
+// `/v2`: the same core with typed replies and without the legacy
+// `device-connected` event
+pub async fn on_connect_v2(socket: SocketRef, Data(data): Data<Value>) {
+    register(&socket, &data);
+
+    socket.on("version", |ack: AckSender| {
+        let _ = ack.send(
+            &(VersionResponse {
+                success: true,
+                command: "version".to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                error: None,
+            })
+        );
+    });
+
+    socket.on("device-info", |ack: AckSender| {
+        let device = serial::find_connected_device_info(VID, PIDS);
+        let _ = ack.send(
+            &(DeviceInfoResponse {
+                success: true,
+                command: "device-info".to_string(),
+                vid: VID,
+                supported_pids: PIDS.to_vec(),
+                device: device_status(device.is_some(), device.as_ref()),
+                error: None,
+            })
+        );
+    });
+
+    socket.on("start-snappy", async |socket: SocketRef, TryData(data): TryData<Value>, ack: AckSender| {
+        let result = start(&socket, data.ok()).await;
+        let _ = ack.send(&collection_response("start-snappy", socket.id, result));
+    });
+
+    socket.on("stop-snappy", |socket: SocketRef, ack: AckSender| {
+        unsubscribe(socket.id);
+        let _ = ack.send(&collection_response("stop-snappy", socket.id, Ok(())));
+    });
+}
+
+fn sorted_macs(macs: &HashSet<String>) -> Vec<String> {
+    let mut macs: Vec<String> = macs.iter().cloned().collect();
+    macs.sort();
+    macs
+}
+
+fn subscription_response(result: Result<Option<Vec<String>>, String>) -> SubscriptionResponse {
+    match result {
+        Ok(macs) =>
+            SubscriptionResponse {
+                success: true,
+                command: "set-subscription".to_string(),
+                macs,
+                error: None,
+            },
+        Err(e) =>
+            SubscriptionResponse {
+                success: false,
+                command: "set-subscription".to_string(),
+                macs: None,
+                error: Some(e),
+            },
+    }
+}
+
+fn device_status(connected: bool, device: Option<&(u16, String)>) -> DeviceStatus {
+    let device_config = &config::get().device;
+    DeviceStatus {
+        connected,
+        vid: VID,
+        pid: device.map(|(pid, _)| *pid),
+        port: device.map(|(_, port)| port.clone()),
+        serial: device
+            .filter(|_| device_config.expose_serial)
+            .and_then(|(_, port)| serial::device_serial(port)),
+        profile: device.map(|(pid, _)| device_config.profile(*pid)),
+        collecting: is_snappy_collecting(),
+        recording: sessions::active_id(),
+    }
+}
+
 fn check_port_connection(socket: SocketRef) {
     tokio::spawn(async move {
         let mut last_status = None;
         let mut last_connected_pid: Option<u16> = None;
+        let mut last_device_status: Option<DeviceStatus> = None;
         
-        loop {
+        // Stop polling once the client is gone or the agent shuts down
+        while socket.connected() && !shutdown::is_shutting_down() {
             // Check if any of our supported devices is connected
             let status = Some(serial::is_any_device_connected(VID, PIDS));
             let connected_device_info = serial::find_connected_device_info(VID, PIDS);
             let current_pid = connected_device_info.as_ref().map(|(pid, _)| *pid);
-            
-            // Emit status if connection status changed or if different device connected
-            if status != last_status || current_pid != last_connected_pid {
+
+            // Also sent when collection or recording starts or stops
+            let device_status = device_status(status == Some(true), connected_device_info.as_ref());
+            if last_device_status.as_ref() != Some(&device_status) {
+                socket.emit("device-status", &device_status).ok();
+                last_device_status = Some(device_status);
+            }
+
+            // Legacy comma-joined status for clients before protocol 2
+            if protocol::of(socket.id) < 2 && (status != last_status || current_pid != last_connected_pid) {
                 let event_response = if let Some((pid, device_name)) = &connected_device_info {
                     EventResponse {
                         event: "device-connection".to_string(),
@@ -149,7 +749,7 @@ fn check_port_connection(socket: SocketRef) {
                 last_connected_pid = current_pid;
             }
             
-            tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+            shutdown::sleep(tokio::time::Duration::from_millis(200)).await;
         }
     });
 }
\ No newline at end of file
@@ -0,0 +1,259 @@
+use std::sync::{ atomic::{ AtomicU64, Ordering }, mpsc, Mutex, OnceLock };
+use std::time::{ Duration, Instant };
+use chrono::{ DateTime, Utc };
+use rusqlite::{ params, params_from_iter, types::Value as SqlValue, Connection };
+use tracing::info;
+use crate::{ config::{ self, StoreConfig }, models::* };
+
+const DEFAULT_HISTORY_LIMIT: u32 = 1000;
+const MAX_HISTORY_LIMIT: u32 = 10_000;
+const MAX_BATCH: usize = 500;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Records waiting for the writer; past this, new ones are dropped
+const QUEUE_CAPACITY: usize = 50_000;
+
+// Events go through a channel to a writer thread so the device loop never waits on disk
+static WRITER: OnceLock<mpsc::SyncSender<StoredSnapData>> = OnceLock::new();
+// Records dropped because the writer fell behind, not yet logged
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+// Separate connection for queries; WAL lets them run while the writer commits
+static READER: OnceLock<Mutex<Connection>> = OnceLock::new();
+
+const SCHEMA: &str =
+    "
+    CREATE TABLE IF NOT EXISTS snap_data (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        device TEXT NOT NULL,
+        mac TEXT NOT NULL,
+        value INTEGER NOT NULL,
+        pid INTEGER NOT NULL,
+        timestamp TEXT NOT NULL,
+        timestamp_ms INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS snap_data_time ON snap_data (timestamp_ms);
+    CREATE INDEX IF NOT EXISTS snap_data_mac_time ON snap_data (mac, timestamp_ms);
+    ";
+
+fn open(store_config: &StoreConfig) -> rusqlite::Result<Connection> {
+    let connection = Connection::open(store_config.path())?;
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.pragma_update(None, "synchronous", "NORMAL")?;
+    connection.busy_timeout(Duration::from_secs(5))?;
+    Ok(connection)
+}
+
+pub fn is_enabled() -> bool {
+    WRITER.get().is_some()
+}
+
+// Open the database and start the writer; a no-op unless [store] is enabled
+pub fn init() -> Result<(), Box<dyn std::error::Error>> {
+    let store_config = &config::get().store;
+    if !store_config.enabled || is_enabled() {
+        return Ok(());
+    }
+    let path = store_config.path();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let writer = open(store_config)?;
+    writer.execute_batch(SCHEMA)?;
+    let reader = open(store_config)?;
+
+    let (tx, rx) = mpsc::sync_channel(QUEUE_CAPACITY);
+    let retention = store_config.clone();
+    std::thread::Builder::new().name("snap-store".to_string()).spawn(move || write_loop(writer, rx, retention))?;
+
+    let _ = READER.set(Mutex::new(reader));
+    let _ = WRITER.set(tx);
+    info!("Recording snap data to {}", path.display());
+    Ok(())
+}
+
+// Queue an event for storage, or count it as dropped when the queue is full
+pub fn record(event: &SnapDataEvent, device: &str) {
+    let Some(writer) = WRITER.get() else {
+        return;
+    };
+    let timestamp_ms = DateTime::parse_from_rfc3339(&event.timestamp)
+        .map(|time| time.timestamp_millis())
+        .unwrap_or_else(|_| Utc::now().timestamp_millis());
+    let record = StoredSnapData {
+        id: 0,
+        device: device.to_string(),
+        mac: event.mac.clone(),
+        value: event.value,
+        pid: event.pid,
+        timestamp: event.timestamp.clone(),
+        timestamp_ms,
+    };
+    if let Err(mpsc::TrySendError::Full(_)) = writer.try_send(record) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn write_loop(mut connection: Connection, rx: mpsc::Receiver<StoredSnapData>, retention: StoreConfig) {
+    let mut last_prune: Option<Instant> = None;
+    loop {
+        if last_prune.is_none_or(|time| time.elapsed() >= PRUNE_INTERVAL) {
+            if let Err(e) = prune(&connection, &retention) {
+                info!("Failed to apply store retention: {}", e);
+            }
+            last_prune = Some(Instant::now());
+        }
+
+        match rx.recv_timeout(PRUNE_INTERVAL) {
+            Ok(first) => {
+                // Commit everything that is already queued in one transaction
+                let mut batch = vec![first];
+                while batch.len() < MAX_BATCH && let Ok(record) = rx.try_recv() {
+                    batch.push(record);
+                }
+                if let Err(e) = insert_batch(&mut connection, &batch) {
+                    info!("Failed to store {} snap data records: {}", batch.len(), e);
+                }
+                let dropped = DROPPED.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    info!("Store queue was full, dropped {} snap data records", dropped);
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                break;
+            }
+        }
+    }
+}
+
+fn insert_batch(connection: &mut Connection, batch: &[StoredSnapData]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut statement = transaction.prepare_cached(
+            "INSERT INTO snap_data (device, mac, value, pid, timestamp, timestamp_ms) VALUES (?1, ?2, ?3, ?4, ?5, ?6)"
+        )?;
+        for record in batch {
+            statement.execute(
+                params![record.device, record.mac, record.value, record.pid, record.timestamp, record.timestamp_ms]
+            )?;
+        }
+    }
+    transaction.commit()
+}
+
+fn prune(connection: &Connection, retention: &StoreConfig) -> rusqlite::Result<()> {
+    let mut removed = 0;
+    if retention.retention_days > 0 {
+        let cutoff = Utc::now() - chrono::Duration::days(retention.retention_days as i64);
+        removed += connection.execute(
+            "DELETE FROM snap_data WHERE timestamp_ms < ?1",
+            params![cutoff.timestamp_millis()]
+        )?;
+    }
+    if retention.max_records > 0 {
+        removed += connection.execute(
+            "DELETE FROM snap_data WHERE id <= (SELECT id FROM snap_data ORDER BY id DESC LIMIT 1 OFFSET ?1)",
+            params![retention.max_records]
+        )?;
+    }
+    if removed > 0 {
+        info!("Removed {} snap data records past the retention policy", removed);
+    }
+    Ok(())
+}
+
+fn parse_time(label: &str, value: &Option<String>) -> Result<Option<i64>, String> {
+    value
+        .as_deref()
+        .map(|value| {
+            DateTime::parse_from_rfc3339(value)
+                .map(|time| time.timestamp_millis())
+                .map_err(|e| format!("invalid '{}' timestamp {:?}: {}", label, value, e))
+        })
+        .transpose()
+}
+
+fn run_query(query: &HistoryQuery) -> Result<(Vec<StoredSnapData>, bool), String> {
+    let mut conditions = Vec::new();
+    let mut values: Vec<SqlValue> = Vec::new();
+    if let Some(from) = parse_time("from", &query.from)? {
+        conditions.push("timestamp_ms >= ?");
+        values.push(from.into());
+    }
+    if let Some(to) = parse_time("to", &query.to)? {
+        conditions.push("timestamp_ms <= ?");
+        values.push(to.into());
+    }
+    if let Some(device) = &query.device {
+        conditions.push("device = ?");
+        values.push(device.clone().into());
+    }
+    if let Some(mac) = &query.mac {
+        conditions.push("mac = ?");
+        values.push(mac.to_lowercase().into());
+    }
+    if let Some(pid) = query.pid {
+        conditions.push("pid = ?");
+        values.push((pid as i64).into());
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT).clamp(1, MAX_HISTORY_LIMIT);
+    // One extra row tells whether the result was cut off
+    values.push(((limit as i64) + 1).into());
+
+    let filter = if conditions.is_empty() { String::new() } else { format!("WHERE {}", conditions.join(" AND ")) };
+    let sql = format!(
+        "SELECT id, device, mac, value, pid, timestamp, timestamp_ms FROM snap_data {} ORDER BY timestamp_ms, id LIMIT ?",
+        filter
+    );
+
+    let reader = READER.get().ok_or("history store is disabled")?;
+    let connection = reader.lock().map_err(|_| "history store is unavailable")?;
+    let mut statement = connection.prepare_cached(&sql).map_err(|e| e.to_string())?;
+    let mut records = statement
+        .query_map(params_from_iter(values), |row| {
+            Ok(StoredSnapData {
+                id: row.get(0)?,
+                device: row.get(1)?,
+                mac: row.get(2)?,
+                value: row.get(3)?,
+                pid: row.get(4)?,
+                timestamp: row.get(5)?,
+                timestamp_ms: row.get(6)?,
+            })
+        })
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+        .map_err(|e| e.to_string())?;
+
+    let truncated = records.len() > (limit as usize);
+    records.truncate(limit as usize);
+    Ok((records, truncated))
+}
+
+// Shared by the REST endpoint and the Socket.IO `history` command
+pub async fn history(query: HistoryQuery) -> HistoryResponse {
+    let result = if is_enabled() {
+        tokio::task
+            ::spawn_blocking(move || run_query(&query)).await
+            .unwrap_or_else(|e| Err(e.to_string()))
+    } else {
+        Err("history store is disabled; set enabled = true under [store]".to_string())
+    };
+    match result {
+        Ok((records, truncated)) =>
+            HistoryResponse {
+                success: true,
+                command: "history".to_string(),
+                records,
+                truncated,
+                error: None,
+            },
+        Err(e) =>
+            HistoryResponse {
+                success: false,
+                command: "history".to_string(),
+                records: Vec::new(),
+                truncated: false,
+                error: Some(e),
+            },
+    }
+}
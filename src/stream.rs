@@ -0,0 +1,181 @@
+use std::collections::{ HashMap, VecDeque };
+use std::sync::{ Mutex, OnceLock };
+use crate::{ config, models::{ LatestValue, SnapDataEvent } };
+
+struct ReplayBuffer {
+    capacity: usize,
+    next_seq: u64,
+    events: VecDeque<SnapDataEvent>,
+    // Newest replayable event that no longer fits the buffer, 0 if none
+    evicted_seq: u64,
+}
+
+impl ReplayBuffer {
+    fn new(capacity: usize) -> Self {
+        ReplayBuffer {
+            capacity,
+            next_seq: 1,
+            events: VecDeque::with_capacity(capacity),
+            evicted_seq: 0,
+        }
+    }
+
+    fn push(&mut self, mut event: SnapDataEvent) -> SnapDataEvent {
+        event.seq = self.next_seq;
+        self.next_seq += 1;
+        if self.capacity == 0 {
+            self.evicted_seq = event.seq;
+            return event;
+        }
+        if self.events.len() >= self.capacity && let Some(evicted) = self.events.pop_front() {
+            self.evicted_seq = evicted.seq;
+        }
+        self.events.push_back(event.clone());
+        event
+    }
+
+    fn newest(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    // `same_run` is false when `last_seq` is known to be from another agent run
+    fn since(&self, last_seq: u64, same_run: bool) -> (Vec<SnapDataEvent>, bool) {
+        if !same_run || last_seq > self.newest() {
+            return (self.events.iter().cloned().collect(), true);
+        }
+        let missed = self.events
+            .iter()
+            .filter(|event| event.seq > last_seq)
+            .cloned()
+            .collect();
+        (missed, last_seq < self.evicted_seq)
+    }
+}
+
+// Identifies this agent run; the start time in Unix milliseconds
+static RUN: OnceLock<u64> = OnceLock::new();
+
+pub fn run_id() -> u64 {
+    *RUN.get_or_init(|| chrono::Utc::now().timestamp_millis() as u64)
+}
+
+// Recently emitted events, kept so a reconnecting client can catch up
+static BUFFER: OnceLock<Mutex<ReplayBuffer>> = OnceLock::new();
+
+fn buffer() -> &'static Mutex<ReplayBuffer> {
+    BUFFER.get_or_init(|| Mutex::new(ReplayBuffer::new(config::get().stream.replay_buffer)))
+}
+
+// Last event per (device, MAC), so a client joining late has values right away
+static LATEST: OnceLock<Mutex<HashMap<(String, String), SnapDataEvent>>> = OnceLock::new();
+
+fn latest() -> &'static Mutex<HashMap<(String, String), SnapDataEvent>> {
+    LATEST.get_or_init(Default::default)
+}
+
+// Give an event of the live stream the next sequence id and remember it for
+// replay. Events kept out of the live stream (debounced) get seq 0, so the ids
+// clients see have no gaps other than lost events.
+pub fn sequence(mut event: SnapDataEvent, live: bool) -> SnapDataEvent {
+    event.run = run_id();
+    if !live {
+        event.seq = 0;
+        return event;
+    }
+    buffer().lock().unwrap_or_else(|e| e.into_inner()).push(event)
+}
+
+// Sequence id of the most recent event, 0 before the first one
+pub fn last_seq() -> u64 {
+    buffer().lock().unwrap_or_else(|e| e.into_inner()).newest()
+}
+
+// Buffered events after `last_seq` of `run`. The flag is true when some of the
+// missed events are no longer buffered, or `last_seq` is from an earlier agent run
+// (known from `run`, or guessed from `last_seq` being ahead when it is omitted).
+pub fn since(last_seq: u64, run: Option<u64>) -> (Vec<SnapDataEvent>, bool) {
+    let same_run = run.is_none_or(|run| run == run_id());
+    buffer().lock().unwrap_or_else(|e| e.into_inner()).since(last_seq, same_run)
+}
+
+pub fn remember(event: &SnapDataEvent, device: &str) {
+    let mut latest = latest().lock().unwrap_or_else(|e| e.into_inner());
+    latest.insert((device.to_string(), event.mac.clone()), event.clone());
+}
+
+// Latest values that pass `wants`, ordered by device and MAC
+pub fn snapshot(wants: impl Fn(&str) -> bool) -> Vec<LatestValue> {
+    let latest = latest().lock().unwrap_or_else(|e| e.into_inner());
+    let mut values: Vec<LatestValue> = latest
+        .iter()
+        .filter(|((_, mac), _)| wants(mac))
+        .map(|((device, _), event)| LatestValue { device: device.clone(), event: event.clone() })
+        .collect();
+    values.sort_by(|a, b| (&a.device, &a.event.mac).cmp(&(&b.device, &b.event.mac)));
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A buffer of `capacity` that has seen `count` live events
+    fn buffer_with(capacity: usize, count: u16) -> ReplayBuffer {
+        let mut buffer = ReplayBuffer::new(capacity);
+        for value in 0..count {
+            buffer.push(SnapDataEvent::raw("aa:bb:cc:dd:ee:ff", value, ""));
+        }
+        buffer
+    }
+
+    // Sequence ids of the replayed events, and the gap flag
+    fn since(buffer: &ReplayBuffer, last_seq: u64, same_run: bool) -> (Vec<u64>, bool) {
+        let (events, gap) = buffer.since(last_seq, same_run);
+        (events.iter().map(|event| event.seq).collect(), gap)
+    }
+
+    #[test]
+    fn live_events_are_numbered_from_one() {
+        let mut buffer = ReplayBuffer::new(10);
+        assert_eq!(buffer.newest(), 0);
+        assert_eq!(buffer.push(SnapDataEvent::raw("aa:bb:cc:dd:ee:ff", 7, "")).seq, 1);
+        assert_eq!(buffer.push(SnapDataEvent::raw("aa:bb:cc:dd:ee:ff", 8, "")).seq, 2);
+        assert_eq!(buffer.newest(), 2);
+    }
+
+    #[test]
+    fn debounced_events_take_no_sequence_id() {
+        let event = sequence(SnapDataEvent::raw("aa:bb:cc:dd:ee:ff", 1, ""), false);
+        assert_eq!(event.seq, 0);
+        assert_eq!(event.run, run_id());
+    }
+
+    #[test]
+    fn since_returns_the_missed_events() {
+        let buffer = buffer_with(10, 5);
+        assert_eq!(since(&buffer, 2, true), (vec![3, 4, 5], false));
+        assert_eq!(since(&buffer, 5, true), (vec![], false));
+    }
+
+    #[test]
+    fn evicted_events_are_a_gap() {
+        let buffer = buffer_with(3, 5);
+        assert_eq!(since(&buffer, 1, true), (vec![3, 4, 5], true));
+        // Everything after the newest evicted event is still buffered
+        assert_eq!(since(&buffer, 2, true), (vec![3, 4, 5], false));
+    }
+
+    #[test]
+    fn another_run_or_a_future_seq_is_a_gap() {
+        let buffer = buffer_with(10, 5);
+        assert_eq!(since(&buffer, 4, false), (vec![1, 2, 3, 4, 5], true));
+        assert_eq!(since(&buffer, 9, true), (vec![1, 2, 3, 4, 5], true));
+    }
+
+    #[test]
+    fn without_a_buffer_every_missed_event_is_a_gap() {
+        let buffer = buffer_with(0, 3);
+        assert_eq!(since(&buffer, 3, true), (vec![], false));
+        assert_eq!(since(&buffer, 2, true), (vec![], true));
+    }
+}
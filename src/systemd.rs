@@ -0,0 +1,388 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+use tracing::info;
+use crate::{ models::*, serial, shutdown, socketio };
+
+// A collection loop that has not reported progress for this long is considered hung
+const DEVICE_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+const HTTP_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+static SUPERVISOR_HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+// Zero while no port is open
+static READER_HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+
+pub enum DeviceLoop {
+    // Polls for the dongle appearing/disappearing
+    Supervisor,
+    // Reads frames from an open port
+    Reader,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Called by the device loops on every iteration
+pub fn device_heartbeat(device_loop: DeviceLoop) {
+    let heartbeat = match device_loop {
+        DeviceLoop::Supervisor => &SUPERVISOR_HEARTBEAT,
+        DeviceLoop::Reader => &READER_HEARTBEAT,
+    };
+    heartbeat.store(now_millis(), Ordering::Relaxed);
+}
+
+// The reader closed its port and is waiting for the next device
+pub fn reader_idle() {
+    READER_HEARTBEAT.store(0, Ordering::Relaxed);
+}
+
+fn device_supervisor_healthy() -> bool {
+    if !socketio::is_snappy_collecting() {
+        return true;
+    }
+    let now = now_millis();
+    let stall = DEVICE_STALL_TIMEOUT.as_millis() as u64;
+    let supervisor = SUPERVISOR_HEARTBEAT.load(Ordering::Relaxed);
+    let reader = READER_HEARTBEAT.load(Ordering::Relaxed);
+    now.saturating_sub(supervisor) < stall && (reader == 0 || now.saturating_sub(reader) < stall)
+}
+
+// Listening sockets handed over by systemd socket activation. Sockets named
+// "tls" (FileDescriptorName=tls) serve HTTPS/WSS, other TCP sockets plain HTTP.
+#[derive(Default)]
+pub struct ActivatedSockets {
+    pub http: Vec<std::net::TcpListener>,
+    pub tls: Vec<std::net::TcpListener>,
+    #[cfg(unix)]
+    pub unix: Vec<std::os::unix::net::UnixListener>,
+}
+
+static LISTEN_FDS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+// How many fds LISTEN_PID/LISTEN_FDS pass to process `pid`; 0 when they are for another
+#[cfg(target_os = "linux")]
+fn listen_fd_count(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> u32 {
+    let for_us = listen_pid.and_then(|listen_pid| listen_pid.parse::<u32>().ok()) == Some(pid);
+    let count = listen_fds.and_then(|count| count.parse().ok()).unwrap_or(0);
+    if for_us { count } else { 0 }
+}
+
+// Take ownership of the sockets passed via LISTEN_FDS (see sd_listen_fds(3)).
+// Only the first call gets them.
+#[cfg(target_os = "linux")]
+pub fn take_listen_fds() -> ActivatedSockets {
+    use std::os::fd::{ FromRawFd, RawFd };
+    use socket2::Socket;
+
+    const LISTEN_FDS_START: RawFd = 3;
+
+    let mut sockets = ActivatedSockets::default();
+    if LISTEN_FDS_TAKEN.swap(true, Ordering::SeqCst) {
+        return sockets;
+    }
+
+    let count = listen_fd_count(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id()
+    ) as RawFd;
+    if count == 0 {
+        return sockets;
+    }
+    let names: Vec<String> = std::env
+        ::var("LISTEN_FDNAMES")
+        .map(|names| names.split(':').map(str::to_string).collect())
+        .unwrap_or_default();
+
+    for index in 0..count {
+        let fd = LISTEN_FDS_START + index;
+        // SAFETY: systemd transfers ownership of fds 3..3+LISTEN_FDS to this process
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        if let Err(e) = socket.set_nonblocking(true) {
+            info!("Ignoring activated socket fd {}: {}", fd, e);
+            continue;
+        }
+        let name = names.get(index as usize).map(String::as_str).unwrap_or("");
+        match socket.local_addr() {
+            Ok(addr) if addr.as_socket().is_some() => {
+                info!("Using activated socket {:?} (name {:?})", addr.as_socket(), name);
+                if name == "tls" {
+                    sockets.tls.push(socket.into());
+                } else {
+                    sockets.http.push(socket.into());
+                }
+            }
+            Ok(addr) if addr.is_unix() => {
+                info!("Using activated Unix socket {:?}", addr.as_pathname());
+                sockets.unix.push(socket.into());
+            }
+            Ok(_) | Err(_) => {
+                info!("Ignoring activated fd {}: not a TCP or Unix socket", fd);
+            }
+        }
+    }
+    sockets
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn take_listen_fds() -> ActivatedSockets {
+    LISTEN_FDS_TAKEN.store(true, Ordering::SeqCst);
+    ActivatedSockets::default()
+}
+
+// Send a state string to the service manager. Returns Ok(false) when the agent
+// is not running under systemd (no NOTIFY_SOCKET).
+#[cfg(target_os = "linux")]
+pub fn notify(state: &str) -> std::io::Result<bool> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{ SocketAddr as UnixAddr, UnixDatagram };
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let path = path.to_string_lossy().into_owned();
+    let socket = UnixDatagram::unbound()?;
+    // A leading '@' denotes a socket in the abstract namespace
+    if let Some(name) = path.strip_prefix('@') {
+        socket.send_to_addr(state.as_bytes(), &UnixAddr::from_abstract_name(name)?)?;
+    } else {
+        socket.send_to(state.as_bytes(), &path)?;
+    }
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn notify(_state: &str) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+fn notify_logged(state: &str) {
+    if let Err(e) = notify(state) {
+        info!("Failed to notify service manager: {}", e);
+    }
+}
+
+// Watchdog interval from WATCHDOG_USEC, unless WATCHDOG_PID names a process other than `pid`
+fn parse_watchdog(usec: Option<&str>, watchdog_pid: Option<&str>, pid: u32) -> Option<Duration> {
+    let usec: u64 = usec?.parse().ok()?;
+    if let Some(watchdog_pid) = watchdog_pid && watchdog_pid.parse::<u32>().ok() != Some(pid) {
+        return None;
+    }
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec))
+}
+
+// Watchdog interval requested by the unit, if it is meant for this process
+fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id()
+    )
+}
+
+// Make a real request against our own listener so a wedged HTTP stack stops the pings
+async fn http_server_healthy(addr: SocketAddr) -> bool {
+    let probe = async {
+        let mut stream = tokio::net::TcpStream::connect(addr).await?;
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<bool, std::io::Error>(response.starts_with(b"HTTP/1.1 200"))
+    };
+    matches!(tokio::time::timeout(HTTP_PROBE_TIMEOUT, probe).await, Ok(Ok(true)))
+}
+
+fn device_status(port: u16) -> String {
+    let device = match serial::find_connected_device_info(VID, PIDS) {
+        Some((pid, device_name)) => format!("device connected (PID 0x{:04x}, {})", pid, device_name),
+        None => "no device".to_string(),
+    };
+    let collecting = if socketio::is_snappy_collecting() { "collecting" } else { "idle" };
+    format!("Listening on port {}; {}; {}", port, device, collecting)
+}
+
+// Report readiness once the listeners are bound, then keep STATUS up to date
+// and pet the watchdog while the HTTP server and device supervisor are healthy
+pub fn ready(port: u16, probe_addr: SocketAddr) {
+    let status = device_status(port);
+    match notify(&format!("READY=1\nSTATUS={}", status)) {
+        Ok(true) => info!("Notified systemd that the agent is ready"),
+        Ok(false) => {
+            return;
+        }
+        Err(e) => {
+            info!("Failed to notify systemd readiness: {}", e);
+            return;
+        }
+    }
+
+    let watchdog = watchdog_interval();
+    if let Some(interval) = watchdog {
+        info!("systemd watchdog enabled with interval {:?}", interval);
+    }
+    // Ping at half the interval as sd_watchdog_enabled(3) recommends
+    let tick = watchdog.map(|interval| (interval / 2).min(STATUS_INTERVAL)).unwrap_or(STATUS_INTERVAL);
+
+    tokio::spawn(async move {
+        let mut last_status = status;
+        while !shutdown::is_shutting_down() {
+            shutdown::sleep(tick).await;
+            if shutdown::is_shutting_down() {
+                break;
+            }
+
+            let status = device_status(port);
+            if status != last_status {
+                notify_logged(&format!("STATUS={}", status));
+                last_status = status;
+            }
+
+            if watchdog.is_some() {
+                let http_ok = http_server_healthy(probe_addr).await;
+                let device_ok = device_supervisor_healthy();
+                if http_ok && device_ok {
+                    notify_logged("WATCHDOG=1");
+                } else {
+                    info!(
+                        "Withholding watchdog ping (http healthy: {}, device supervisor healthy: {})",
+                        http_ok,
+                        device_ok
+                    );
+                }
+            }
+        }
+    });
+}
+
+pub fn stopping() {
+    notify_logged("STOPPING=1\nSTATUS=Shutting down");
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    // NOTIFY_SOCKET and WATCHDOG_* are process-wide
+    static ENV: Mutex<()> = Mutex::new(());
+
+    // A datagram socket standing in for systemd, with NOTIFY_SOCKET pointing at it
+    struct NotifySocket {
+        socket: UnixDatagram,
+        path: PathBuf,
+    }
+
+    impl NotifySocket {
+        fn bind(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("snappy-notify-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_file(&path);
+            let socket = UnixDatagram::bind(&path).unwrap();
+            socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            // SAFETY: tests that touch the environment hold ENV
+            unsafe { std::env::set_var("NOTIFY_SOCKET", &path) };
+            NotifySocket { socket, path }
+        }
+
+        fn recv(&self) -> String {
+            let mut buffer = [0u8; 1024];
+            let len = self.socket.recv(&mut buffer).unwrap();
+            String::from_utf8_lossy(&buffer[..len]).into_owned()
+        }
+    }
+
+    impl Drop for NotifySocket {
+        fn drop(&mut self) {
+            // SAFETY: tests that touch the environment hold ENV
+            unsafe { std::env::remove_var("NOTIFY_SOCKET") };
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    // Answers every connection with `200 OK`, like a healthy HTTP listener
+    async fn healthy_listener() -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).await;
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn notify_without_socket_is_a_no_op() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        // SAFETY: tests that touch the environment hold ENV
+        unsafe { std::env::remove_var("NOTIFY_SOCKET") };
+        assert!(!notify("READY=1").unwrap());
+    }
+
+    #[test]
+    fn notify_sends_the_state() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let systemd = NotifySocket::bind("state");
+        assert!(notify("STATUS=testing").unwrap());
+        assert_eq!(systemd.recv(), "STATUS=testing");
+        stopping();
+        assert_eq!(systemd.recv(), "STOPPING=1\nSTATUS=Shutting down");
+    }
+
+    #[test]
+    fn ready_reports_status_and_pets_the_watchdog() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let systemd = NotifySocket::bind("ready");
+        // SAFETY: tests that touch the environment hold ENV
+        unsafe {
+            std::env::set_var("WATCHDOG_USEC", "200000");
+            std::env::set_var("WATCHDOG_PID", std::process::id().to_string());
+        }
+        // The watchdog task runs on the runtime's workers while this thread waits for pings
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async { ready(8436, healthy_listener().await) });
+
+        let ready_state = systemd.recv();
+        assert!(ready_state.starts_with("READY=1\nSTATUS=Listening on port 8436; "), "{}", ready_state);
+        assert_eq!(systemd.recv(), "WATCHDOG=1");
+
+        // SAFETY: tests that touch the environment hold ENV
+        unsafe {
+            std::env::remove_var("WATCHDOG_USEC");
+            std::env::remove_var("WATCHDOG_PID");
+        }
+    }
+
+    #[test]
+    fn watchdog_interval_is_for_this_process_only() {
+        assert_eq!(parse_watchdog(Some("2000000"), None, 42), Some(Duration::from_secs(2)));
+        assert_eq!(parse_watchdog(Some("2000000"), Some("42"), 42), Some(Duration::from_secs(2)));
+        assert_eq!(parse_watchdog(Some("2000000"), Some("43"), 42), None);
+        assert_eq!(parse_watchdog(Some("2000000"), Some("nope"), 42), None);
+        assert_eq!(parse_watchdog(Some("0"), None, 42), None);
+        assert_eq!(parse_watchdog(Some("soon"), None, 42), None);
+        assert_eq!(parse_watchdog(None, Some("42"), 42), None);
+    }
+
+    #[test]
+    fn listen_fds_are_for_this_process_only() {
+        assert_eq!(listen_fd_count(Some("42"), Some("2"), 42), 2);
+        assert_eq!(listen_fd_count(Some("43"), Some("2"), 42), 0);
+        assert_eq!(listen_fd_count(None, Some("2"), 42), 0);
+        assert_eq!(listen_fd_count(Some("42"), None, 42), 0);
+        assert_eq!(listen_fd_count(Some("42"), Some("-1"), 42), 0);
+        assert_eq!(listen_fd_count(Some("pid"), Some("2"), 42), 0);
+    }
+}
@@ -0,0 +1,249 @@
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::time::{ Duration, SystemTime };
+use chrono::{ Datelike, Utc };
+use rcgen::{
+    BasicConstraints,
+    CertificateParams,
+    CidrSubnet,
+    DistinguishedName,
+    DnType,
+    ExtendedKeyUsagePurpose,
+    GeneralSubtree,
+    IsCa,
+    Issuer,
+    KeyPair,
+    KeyUsagePurpose,
+    NameConstraints,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use tracing::info;
+use crate::shutdown;
+
+const CA_CERT_FILE: &str = "ca.pem";
+const CA_KEY_FILE: &str = "ca-key.pem";
+const SERVER_CERT_FILE: &str = "localhost.pem";
+const SERVER_KEY_FILE: &str = "localhost-key.pem";
+
+const CA_VALIDITY_DAYS: i64 = 3650;
+// Apple platforms reject server certificates valid for more than 825 days,
+// so keep the leaf short-lived and renew it well before it expires
+const SERVER_VALIDITY_DAYS: i64 = 397;
+const SERVER_RENEW_AFTER: Duration = Duration::from_secs(300 * 24 * 60 * 60);
+// How often a running agent checks whether its certificate is due for renewal
+const RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+pub struct CertificatePaths {
+    pub ca_cert: PathBuf,
+    pub server_cert: PathBuf,
+    pub server_key: PathBuf,
+}
+
+fn set_validity(params: &mut CertificateParams, days: i64) {
+    let from = (Utc::now() - chrono::Duration::days(1)).date_naive();
+    let until = (Utc::now() + chrono::Duration::days(days)).date_naive();
+    params.not_before = rcgen::date_time_ymd(from.year(), from.month() as u8, from.day() as u8);
+    params.not_after = rcgen::date_time_ymd(until.year(), until.month() as u8, until.day() as u8);
+}
+
+fn ca_params() -> CertificateParams {
+    let mut params = CertificateParams::default();
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, "Snappy Web Agent Local CA");
+    name.push(DnType::OrganizationName, "YuduRobotics");
+    params.distinguished_name = name;
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    // The CA is trusted system-wide; whoever gets its key must not be able to
+    // vouch for anything but this machine
+    params.name_constraints = Some(NameConstraints {
+        permitted_subtrees: vec![
+            GeneralSubtree::DnsName("localhost".to_string()),
+            GeneralSubtree::IpAddress(CidrSubnet::from_v4_prefix([127, 0, 0, 1], 32)),
+            GeneralSubtree::IpAddress(CidrSubnet::from_v6_prefix(std::net::Ipv6Addr::LOCALHOST.octets(), 128)),
+        ],
+        excluded_subtrees: Vec::new(),
+    });
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    set_validity(&mut params, CA_VALIDITY_DAYS);
+    params
+}
+
+fn server_params() -> Result<CertificateParams, rcgen::Error> {
+    let mut params = CertificateParams::new(
+        vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()]
+    )?;
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, "localhost");
+    params.distinguished_name = name;
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    set_validity(&mut params, SERVER_VALIDITY_DAYS);
+    Ok(params)
+}
+
+// The file is private from the moment it exists, before the key is written
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e);
+        }
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    // ProgramData is readable by every user; keep only SYSTEM and Administrators
+    #[cfg(windows)]
+    {
+        let status = std::process::Command
+            ::new("icacls")
+            .arg(path)
+            .args(["/inheritance:r", "/grant:r", "*S-1-5-18:F", "*S-1-5-32-544:F"])
+            .stdout(std::process::Stdio::null())
+            .status()?;
+        if !status.success() {
+            drop(file);
+            let _ = fs::remove_file(path);
+            return Err(std::io::Error::other(format!("could not restrict access to {}", path.display())));
+        }
+    }
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()
+}
+
+// CAs from before name constraints were added can sign for any domain
+fn is_constrained(ca_cert_pem: &str) -> bool {
+    x509_parser::pem
+        ::parse_x509_pem(ca_cert_pem.as_bytes())
+        .ok()
+        .and_then(|(_, pem)| pem.parse_x509().ok().map(|cert| matches!(cert.name_constraints(), Ok(Some(_)))))
+        .unwrap_or(false)
+}
+
+fn needs_renewal(cert_path: &Path) -> bool {
+    let modified = match fs::metadata(cert_path).and_then(|m| m.modified()) {
+        Ok(modified) => modified,
+        Err(_) => {
+            return true;
+        }
+    };
+    SystemTime::now()
+        .duration_since(modified)
+        .map(|age| age > SERVER_RENEW_AFTER)
+        .unwrap_or(false)
+}
+
+// Make sure a local CA and a localhost certificate signed by it exist in `dir`,
+// creating or renewing them as needed; true if the localhost certificate changed
+pub fn ensure_certificates(dir: &Path) -> Result<(CertificatePaths, bool), Box<dyn std::error::Error>> {
+    fs::create_dir_all(dir)?;
+
+    let paths = CertificatePaths {
+        ca_cert: dir.join(CA_CERT_FILE),
+        server_cert: dir.join(SERVER_CERT_FILE),
+        server_key: dir.join(SERVER_KEY_FILE),
+    };
+    let ca_key_path = dir.join(CA_KEY_FILE);
+
+    let existing_ca = fs::read_to_string(&paths.ca_cert).ok().filter(|pem| ca_key_path.exists() && is_constrained(pem));
+    if existing_ca.is_none() && paths.ca_cert.exists() {
+        info!("Replacing the unconstrained local certificate authority; trust the new one with --export-ca");
+    }
+    let (ca_cert_pem, ca_key) = if let Some(ca_cert_pem) = existing_ca {
+        let ca_key = KeyPair::from_pem(&fs::read_to_string(&ca_key_path)?)?;
+        (ca_cert_pem, ca_key)
+    } else {
+        info!("Generating local certificate authority in {}", dir.display());
+        let ca_key = KeyPair::generate()?;
+        let ca_cert = ca_params().self_signed(&ca_key)?;
+        write_private(&ca_key_path, &ca_key.serialize_pem())?;
+        fs::write(&paths.ca_cert, ca_cert.pem())?;
+        // A new CA invalidates any server certificate issued by the old one
+        let _ = fs::remove_file(&paths.server_cert);
+        (ca_cert.pem(), ca_key)
+    };
+
+    let renew = needs_renewal(&paths.server_cert) || !paths.server_key.exists();
+    if renew {
+        info!("Issuing localhost certificate in {}", dir.display());
+        let issuer = Issuer::from_ca_cert_pem(&ca_cert_pem, ca_key)?;
+        let server_key = KeyPair::generate()?;
+        let server_cert = server_params()?.signed_by(&server_key, &issuer)?;
+        write_private(&paths.server_key, &server_key.serialize_pem())?;
+        // Serve the full chain so clients that only trust the CA can verify it
+        fs::write(&paths.server_cert, format!("{}{}", server_cert.pem(), ca_cert_pem))?;
+    }
+
+    Ok((paths, renew))
+}
+
+// Copy the CA certificate somewhere an installer can pick it up and trust it
+pub fn export_ca(dir: &Path, destination: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let (paths, _) = ensure_certificates(dir)?;
+    if let Some(parent) = destination.parent() && !parent.as_os_str().is_empty() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(&paths.ca_cert, destination)?;
+    Ok(())
+}
+
+// Create or renew the certificates and load them, before privileges are dropped
+pub async fn load_config(dir: &Path) -> Result<RustlsConfig, Box<dyn std::error::Error>> {
+    // Several crates could pull in a rustls provider; be explicit about ours
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    let (paths, _) = ensure_certificates(dir)?;
+    Ok(RustlsConfig::from_pem_file(&paths.server_cert, &paths.server_key).await?)
+}
+
+// Renew the localhost certificate while the agent runs and serve the new one
+fn watch_renewal(dir: PathBuf, config: RustlsConfig) {
+    tokio::spawn(async move {
+        while !shutdown::is_shutting_down() {
+            shutdown::sleep(RENEWAL_CHECK_INTERVAL).await;
+            // Errors are not Send, so turn them into text before awaiting
+            match ensure_certificates(&dir).map_err(|e| e.to_string()) {
+                Ok((paths, true)) => {
+                    match config.reload_from_pem_file(&paths.server_cert, &paths.server_key).await {
+                        Ok(()) => info!("Serving the renewed localhost certificate"),
+                        Err(e) => info!("Failed to load the renewed localhost certificate: {}", e),
+                    }
+                }
+                Ok((_, false)) => {}
+                Err(e) => info!("Failed to renew the localhost certificate: {}", e),
+            }
+        }
+    });
+}
+
+pub async fn serve_tls(
+    app: axum::Router,
+    listeners: Vec<std::net::TcpListener>,
+    config: RustlsConfig,
+    cert_dir: PathBuf
+) -> Result<(), Box<dyn std::error::Error>> {
+    watch_renewal(cert_dir, config.clone());
+    let handle = axum_server::Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown::token().cancelled().await;
+        shutdown_handle.graceful_shutdown(Some(shutdown::SHUTDOWN_TIMEOUT));
+    });
+
+    let mut servers = Vec::new();
+    for listener in listeners {
+        info!("Starting HTTPS/WSS listener on {}...", listener.local_addr()?);
+        let server = axum_server::from_tcp_rustls(listener, config.clone())?.handle(handle.clone());
+        servers.push(tokio::spawn(server.serve(app.clone().into_make_service())));
+    }
+    for server in servers {
+        server.await??;
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use tracing::info;
+use crate::{ config::{ self, TransformConfig, TransformRule }, filter, models::SnapDataEvent };
+
+struct Rules {
+    default: Option<TransformRule>,
+    by_pid: HashMap<u16, TransformRule>,
+    by_mac: HashMap<String, TransformRule>,
+}
+
+// [transform] from the config, with its keys parsed once
+static RULES: OnceLock<Rules> = OnceLock::new();
+
+fn rules() -> &'static Rules {
+    RULES.get_or_init(|| parse_rules(&config::get().transform))
+}
+
+fn parse_rules(transform: &TransformConfig) -> Rules {
+    let mut by_pid = HashMap::new();
+    for (key, rule) in &transform.pid {
+        match config::parse_pid(key) {
+            Some(pid) => {
+                by_pid.insert(pid, rule.clone());
+            }
+            None => info!("Ignoring transform for invalid PID {:?}", key),
+        }
+    }
+    let mut by_mac = HashMap::new();
+    for (key, rule) in &transform.mac {
+        match filter::parse_mac(key) {
+            Some(mac) => {
+                by_mac.insert(mac, rule.clone());
+            }
+            None => info!("Ignoring transform for invalid MAC {:?}", key),
+        }
+    }
+    Rules { default: transform.default.clone(), by_pid, by_mac }
+}
+
+fn calibrate(rule: &TransformRule, raw: u16) -> f64 {
+    let reading = if rule.signed { (raw as i16) as f64 } else { raw as f64 };
+    let mut value = reading * rule.scale + rule.offset;
+    if let Some(min) = rule.min {
+        value = value.max(min);
+    }
+    if let Some(max) = rule.max {
+        value = value.min(max);
+    }
+    value
+}
+
+// Fill in the calibrated value and unit from the most specific rule: the
+// peripheral's MAC, then the dongle's PID, then the default. `value` stays raw.
+pub fn apply(event: &mut SnapDataEvent) {
+    apply_rules(rules(), event);
+}
+
+fn apply_rules(rules: &Rules, event: &mut SnapDataEvent) {
+    let rule = rules.by_mac
+        .get(&event.mac)
+        .or_else(|| rules.by_pid.get(&event.pid))
+        .or(rules.default.as_ref());
+    if let Some(rule) = rule {
+        event.calibrated = Some(calibrate(rule, event.value));
+        event.unit = rule.unit.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: &str = "aa:bb:cc:dd:ee:ff";
+
+    fn rule(scale: f64, unit: &str) -> TransformRule {
+        TransformRule { scale, unit: Some(unit.to_string()), ..Default::default() }
+    }
+
+    #[test]
+    fn calibrate_scales_offsets_and_clamps() {
+        let rule = TransformRule { scale: 0.5, offset: -10.0, ..Default::default() };
+        assert_eq!(calibrate(&rule, 100), 40.0);
+
+        let clamped = TransformRule { min: Some(0.0), max: Some(50.0), ..rule.clone() };
+        assert_eq!(calibrate(&clamped, 0), 0.0);
+        assert_eq!(calibrate(&clamped, 1000), 50.0);
+    }
+
+    #[test]
+    fn calibrate_reads_signed_values() {
+        let signed = TransformRule { signed: true, ..Default::default() };
+        assert_eq!(calibrate(&signed, 0xffff), -1.0);
+        assert_eq!(calibrate(&TransformRule::default(), 0xffff), 65535.0);
+    }
+
+    #[test]
+    fn mac_rule_wins_over_pid_rule_over_default() {
+        let transform = TransformConfig {
+            default: Some(rule(1.0, "default")),
+            pid: HashMap::from([(format!("0x{:04x}", crate::models::PID), rule(2.0, "pid"))]),
+            mac: HashMap::from([("AA-BB-CC-DD-EE-FF".to_string(), rule(3.0, "mac"))]),
+        };
+        let rules = parse_rules(&transform);
+
+        let mut by_mac = SnapDataEvent::raw(MAC, 10, "");
+        apply_rules(&rules, &mut by_mac);
+        assert_eq!((by_mac.calibrated, by_mac.unit.as_deref()), (Some(30.0), Some("mac")));
+
+        let mut by_pid = SnapDataEvent::raw("11:22:33:44:55:66", 10, "");
+        apply_rules(&rules, &mut by_pid);
+        assert_eq!((by_pid.calibrated, by_pid.unit.as_deref()), (Some(20.0), Some("pid")));
+
+        let mut by_default = SnapDataEvent::raw("11:22:33:44:55:66", 10, "");
+        by_default.pid = 0x1234;
+        apply_rules(&rules, &mut by_default);
+        assert_eq!((by_default.calibrated, by_default.unit.as_deref()), (Some(10.0), Some("default")));
+        assert_eq!(by_default.value, 10);
+    }
+
+    #[test]
+    fn invalid_keys_are_ignored() {
+        let transform = TransformConfig {
+            default: None,
+            pid: HashMap::from([("0xnope".to_string(), rule(2.0, "pid"))]),
+            mac: HashMap::from([("not-a-mac".to_string(), rule(3.0, "mac"))]),
+        };
+        let rules = parse_rules(&transform);
+        assert!(rules.by_pid.is_empty() && rules.by_mac.is_empty());
+
+        let mut event = SnapDataEvent::raw(MAC, 10, "");
+        apply_rules(&rules, &mut event);
+        assert_eq!(event.calibrated, None);
+    }
+}